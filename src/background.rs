@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::computer::{Computer, StopReason};
+
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+
+// Runs a shared Computer on a worker thread. The lock is only held for one
+// batch of instructions at a time, so other threads always see the machine
+// between instructions. Setting `interrupt` also ends the run; it is static
+// so that a signal handler can set it.
+pub struct Background {
+    computer: Arc<Mutex<Computer>>,
+    stop: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    handle: JoinHandle<StopReason>
+}
+
+impl Background {
+    pub fn start(computer: Arc<Mutex<Computer>>, speed: Option<u64>, interrupt: &'static AtomicBool) -> Background {
+        let stop = Arc::new(AtomicBool::new(false));
+        let paused = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let computer = computer.clone();
+            let stop = stop.clone();
+            let paused = paused.clone();
+
+            std::thread::spawn(move || run(&computer, speed, &stop, interrupt, &paused))
+        };
+
+        Background { computer, stop, paused, handle }
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    // Stops the run at the next instruction boundary, or collects the result
+    // of one that has already finished. A worker that panicked is reported
+    // as an error and its lock released, so the machine stays usable.
+    pub fn stop(self) -> Result<StopReason, String> {
+        self.stop.store(true, Ordering::Relaxed);
+
+        self.handle.join().map_err(|panic| {
+            self.computer.clear_poison();
+
+            let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown error".to_string());
+
+            format!("Background run failed: {}", message)
+        })
+    }
+}
+
+fn run(computer: &Mutex<Computer>, speed: Option<u64>, stop: &AtomicBool, interrupt: &AtomicBool, paused: &AtomicBool) -> StopReason {
+    let mut throttle = computer.lock().unwrap().start_run(speed);
+
+    loop {
+        if stop.load(Ordering::Relaxed) || interrupt.load(Ordering::Relaxed) {
+            return StopReason::Interrupted;
+        }
+
+        // The throttle restarts its schedule after a long pause instead of
+        // racing to make up the time.
+        if paused.load(Ordering::Relaxed) {
+            std::thread::sleep(PAUSE_POLL);
+            continue;
+        }
+
+        let delay = {
+            let mut computer = computer.lock().unwrap();
+            let delay = throttle.delay(computer.cycles);
+
+            if delay.is_zero() {
+                if let Some(reason) = computer.run_batch(throttle.batch(), stop) {
+                    return reason;
+                }
+            }
+
+            delay
+        };
+
+        // Gives a waiting REPL command the chance to take the lock.
+        if delay.is_zero() {
+            std::thread::yield_now();
+        } else {
+            std::thread::sleep(delay);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use processor_emulator::eeprom::{microcode_images, segment_decoder_image};
+
+// Writes EEPROM images for the hardware build:
+//   eeprom microcode <prefix> [2K|8K|32K]  writes <prefix>_0.bin to <prefix>_3.bin
+//   eeprom segments <prefix> [2K|8K|32K]   writes <prefix>.bin
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() != 3 && args.len() != 4 {
+        eprintln!("Usage: eeprom <microcode|segments> <prefix> [2K|8K|32K]");
+        std::process::exit(1);
+    }
+
+    let size = match args.get(3).map(|size| size.as_str()) {
+        None => None,
+        Some("2K") => Some(0x800),
+        Some("8K") => Some(0x2000),
+        Some("32K") => Some(0x8000),
+        Some(size) => {
+            eprintln!("Invalid chip size {}", size);
+            std::process::exit(1);
+        }
+    };
+
+    let prefix = &args[2];
+
+    let images = match args[1].as_str() {
+        "microcode" => microcode_images(size).map(|images| {
+            images.into_iter().enumerate().map(|(chip, image)| (format!("{}_{}.bin", prefix, chip), image)).collect()
+        }),
+        "segments" => segment_decoder_image(size).map(|image| vec![(format!("{}.bin", prefix), image)]),
+        _ => Err(String::from("Invalid image, expected microcode or segments"))
+    };
+
+    let images: Vec<(String, Vec<u8>)> = match images {
+        Ok(images) => images,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+
+    for (file_name, image) in images {
+        if let Err(error) = std::fs::write(&file_name, &image) {
+            eprintln!("Could not write {}: {}", file_name, error);
+            std::process::exit(1);
+        }
+
+        println!("{} ({} bytes)", file_name, image.len());
+    }
+}
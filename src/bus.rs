@@ -0,0 +1,621 @@
+use std::any::Any;
+
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, value: u8);
+
+    // Side-effect free read for the debugger, so inspecting a device register
+    // does not consume its data.
+    fn peek(&self, addr: u16) -> u8;
+}
+
+// A peripheral mapped onto the bus. Offsets are relative to the start of the
+// region the device is mapped at. Devices are Send so a Computer can run on a
+// worker thread.
+pub trait Device: AsAny + Send {
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, value: u8);
+    fn peek(&self, offset: u16) -> u8;
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Called after every step with the emulated cycles it took.
+    fn tick(&mut self, _cycles: u64) {}
+
+    // Devices that want to drive the bus themselves return true here and are
+    // then handed the bus through master(), which returns the cycles the CPU
+    // is stalled for. The device's own region is unmapped while it runs.
+    fn wants_bus(&self) -> bool {
+        false
+    }
+
+    fn master(&mut self, _bus: &mut dyn Bus) -> u64 {
+        0
+    }
+}
+
+// Lets the REPL and library users get the concrete device back from the bus.
+pub trait AsAny: Any {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub struct Memory {
+    pub memory: [u8; 0x10000]
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            memory: [0; 0x10000],
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub const PAGE_READ: u8 = 0x00;
+pub const PAGE_WRITE: u8 = 0x01;
+pub const PAGE_EXECUTE: u8 = 0x02;
+
+pub const PAGE_SIZE: usize = 0x100;
+
+const PAGE_DEFAULT: u8 = (1 << PAGE_READ) | (1 << PAGE_WRITE) | (1 << PAGE_EXECUTE);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WritePolicy {
+    Ignore,
+    Log,
+    Fault
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BusFault {
+    pub addr: u16,
+    pub value: u8
+}
+
+// A read or write made through the bus, kept while recording is on.
+#[derive(Copy, Clone, Debug)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool
+}
+
+pub struct Rom {
+    pub data: Vec<u8>,
+    pub policy: WritePolicy
+}
+
+// A window onto a physical memory larger than the window itself, showing one
+// window-sized bank at a time. Banks are ROM when a write policy is set.
+pub struct Banked {
+    pub data: Vec<u8>,
+    pub banks: usize,
+    pub bank: u8,
+    pub policy: Option<WritePolicy>
+}
+
+pub enum RegionKind {
+    Rom(Rom),
+    Device(Box<dyn Device>),
+    Banked(Banked),
+    // Register selecting the bank shown in the named window.
+    BankSelect(String),
+    // The page permission table, one byte per page.
+    PageTable
+}
+
+pub struct Region {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind
+}
+
+// Routes each address to the region mapped over it, falling back to RAM for
+// anything unmapped.
+pub struct SystemBus {
+    pub ram: Memory,
+    pub regions: Vec<Region>,
+    // User mode permissions for each 256 byte page, PAGE_* bits.
+    pub permissions: [u8; 0x100],
+    fault: Option<BusFault>,
+    recording: bool,
+    accesses: Vec<BusAccess>
+}
+
+impl SystemBus {
+    pub fn new() -> SystemBus {
+        SystemBus {
+            ram: Memory::new(),
+            regions: Vec::new(),
+            permissions: [PAGE_DEFAULT; 0x100],
+            fault: None,
+            recording: false,
+            accesses: Vec::new()
+        }
+    }
+
+    // Clears RAM, banked RAM and the page permissions and selects bank 0 in
+    // every window. ROM contents and the memory map are kept.
+    pub fn power_on(&mut self) {
+        self.ram = Memory::new();
+        self.permissions = [PAGE_DEFAULT; 0x100];
+        self.fault = None;
+
+        for region in &mut self.regions {
+            if let RegionKind::Banked(banked) = &mut region.kind {
+                banked.bank = 0;
+
+                if banked.policy.is_none() {
+                    banked.data.fill(0);
+                }
+            }
+        }
+    }
+
+    // The ROM starts out with whatever RAM held over the range, so a loaded
+    // program can be marked read-only in place.
+    pub fn map_rom(&mut self, name: &str, start: u16, end: u16, policy: WritePolicy) -> Result<(), String> {
+        let data = (start..=end).map(|addr| self.ram.read(addr)).collect();
+
+        self.map(name, start, end, RegionKind::Rom(Rom { data, policy }))
+    }
+
+    pub fn map_device(&mut self, name: &str, start: u16, end: u16, device: Box<dyn Device>) -> Result<(), String> {
+        self.map(name, start, end, RegionKind::Device(device))
+    }
+
+    // Maps the window and its bank select register, named <name>_SELECT.
+    pub fn map_banked(&mut self, name: &str, start: u16, end: u16, banks: usize, register: u16, policy: Option<WritePolicy>) -> Result<(), String> {
+        if banks == 0 || banks > 0x100 {
+            return Err(format!("Region {} needs between 1 and 256 banks", name));
+        }
+
+        // Checked before the banks are allocated from the size.
+        if end < start {
+            return Err(format!("Region {} ends before it starts", name));
+        }
+
+        let size = (end - start) as usize + 1;
+        let banked = Banked {
+            data: vec![0; size * banks],
+            banks,
+            bank: 0,
+            policy
+        };
+
+        self.map(name, start, end, RegionKind::Banked(banked))?;
+
+        let select_name = format!("{}_SELECT", name);
+
+        if let Err(error) = self.map(&select_name, register, register, RegionKind::BankSelect(name.to_string())) {
+            self.regions.pop();
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    pub fn select_bank(&mut self, name: &str, bank: u8) -> Result<(), String> {
+        match self.regions.iter_mut().find(|region| region.name == name).map(|region| &mut region.kind) {
+            Some(RegionKind::Banked(banked)) => {
+                banked.bank = (bank as usize % banked.banks) as u8;
+                Ok(())
+            },
+            _ => Err(format!("No banked region named {}", name))
+        }
+    }
+
+    fn selected_bank(&self, name: &str) -> u8 {
+        match self.regions.iter().find(|region| region.name == name).map(|region| &region.kind) {
+            Some(RegionKind::Banked(banked)) => banked.bank,
+            _ => 0
+        }
+    }
+
+    // Peeks at addr as if the given bank were selected in whichever window
+    // covers it, so any physical page can be inspected.
+    pub fn peek_bank(&self, addr: u16, bank: u8) -> u8 {
+        if let Some(index) = self.region_index(addr) {
+            let region = &self.regions[index];
+
+            if let RegionKind::Banked(banked) = &region.kind {
+                let physical = banked_index(region.start, region.end, bank, addr - region.start);
+
+                return banked.data.get(physical).copied().unwrap_or(0);
+            }
+        }
+
+        self.peek(addr)
+    }
+
+    // Makes the permission table readable and writable at start, so a
+    // supervisor can manage it. Protect its own page from user code.
+    pub fn map_page_table(&mut self, name: &str, start: u16) -> Result<(), String> {
+        let end = start.checked_add(0xFF).ok_or(format!("Region {} does not fit in memory", name))?;
+
+        self.map(name, start, end, RegionKind::PageTable)
+    }
+
+    pub fn allowed(&self, addr: u16, permission: u8) -> bool {
+        self.permissions[addr as usize / PAGE_SIZE] & (1 << permission) != 0
+    }
+
+    // Unmapping a banked window also removes its select register, which
+    // can't be unmapped on its own.
+    pub fn unmap(&mut self, name: &str) -> Result<(), String> {
+        match self.regions.iter().find(|region| region.name == name).map(|region| &region.kind) {
+            Some(RegionKind::BankSelect(window)) => Err(format!("Region {} belongs to {}, unmap that instead", name, window)),
+            Some(_) => {
+                self.regions.retain(|region| match &region.kind {
+                    RegionKind::BankSelect(window) => window != name,
+                    _ => region.name != name
+                });
+                Ok(())
+            },
+            None => Err(format!("No region named {}", name))
+        }
+    }
+
+    fn map(&mut self, name: &str, start: u16, end: u16, kind: RegionKind) -> Result<(), String> {
+        if end < start {
+            return Err(format!("Region {} ends before it starts", name));
+        }
+
+        if self.regions.iter().any(|region| region.name == name) {
+            return Err(format!("Region {} is already mapped", name));
+        }
+
+        if let Some(region) = self.regions.iter().find(|region| start <= region.end && region.start <= end) {
+            return Err(format!("Region {} overlaps {}", name, region.name));
+        }
+
+        self.regions.push(Region {
+            name: name.to_string(),
+            start,
+            end,
+            kind
+        });
+
+        Ok(())
+    }
+
+    // Programs ROM contents like an EEPROM burner; everywhere else this is an
+    // ordinary write.
+    pub fn burn(&mut self, addr: u16, value: u8) {
+        if let Some(index) = self.region_index(addr) {
+            let region = &mut self.regions[index];
+
+            let offset = addr - region.start;
+
+            match &mut region.kind {
+                RegionKind::Rom(rom) => {
+                    rom.data[offset as usize] = value;
+                    return;
+                },
+                RegionKind::Banked(banked) => {
+                    let physical = banked_index(region.start, region.end, banked.bank, offset);
+                    banked.data[physical] = value;
+                    return;
+                },
+                _ => {}
+            }
+        }
+
+        self.write(addr, value);
+    }
+
+    pub fn device<T: Device>(&self, name: &str) -> Option<&T> {
+        match &self.regions.iter().find(|region| region.name == name)?.kind {
+            RegionKind::Device(device) => device.as_ref().as_any().downcast_ref::<T>(),
+            _ => None
+        }
+    }
+
+    pub fn device_mut<T: Device>(&mut self, name: &str) -> Option<&mut T> {
+        match &mut self.regions.iter_mut().find(|region| region.name == name)?.kind {
+            RegionKind::Device(device) => device.as_mut().as_any_mut().downcast_mut::<T>(),
+            _ => None
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.regions.iter().any(|region| match &region.kind {
+            RegionKind::Device(device) => device.irq(),
+            _ => false
+        })
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        for region in &mut self.regions {
+            if let RegionKind::Device(device) = &mut region.kind {
+                device.tick(cycles);
+            }
+        }
+    }
+
+    pub fn run_bus_masters(&mut self) -> u64 {
+        let mut stall = 0;
+
+        for index in 0..self.regions.len() {
+            let wants_bus = match &self.regions[index].kind {
+                RegionKind::Device(device) => device.wants_bus(),
+                _ => false
+            };
+
+            if !wants_bus {
+                continue;
+            }
+
+            let mut region = self.regions.remove(index);
+
+            if let RegionKind::Device(device) = &mut region.kind {
+                stall += device.master(self);
+            }
+
+            self.regions.insert(index, region);
+        }
+
+        stall
+    }
+
+    pub fn take_fault(&mut self) -> Option<BusFault> {
+        self.fault.take()
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        self.accesses.clear();
+    }
+
+    pub fn take_accesses(&mut self) -> Vec<BusAccess> {
+        std::mem::take(&mut self.accesses)
+    }
+
+    fn record(&mut self, addr: u16, value: u8, write: bool) {
+        if self.recording {
+            self.accesses.push(BusAccess { addr, value, write });
+        }
+    }
+
+    fn region_index(&self, addr: u16) -> Option<usize> {
+        self.regions.iter().position(|region| region.start <= addr && addr <= region.end)
+    }
+
+    pub fn print_map(&self) {
+        for region in &self.regions {
+            let kind = match &region.kind {
+                RegionKind::Rom(rom) => match rom.policy {
+                    WritePolicy::Ignore => "ROM (IGNORE)".to_string(),
+                    WritePolicy::Log => "ROM (LOG)".to_string(),
+                    WritePolicy::Fault => "ROM (FAULT)".to_string()
+                },
+                RegionKind::Device(_) => "DEVICE".to_string(),
+                RegionKind::Banked(banked) => format!("{} (BANK {} OF {})", if banked.policy.is_some() { "BANKED ROM" } else { "BANKED RAM" }, banked.bank, banked.banks),
+                RegionKind::BankSelect(window) => format!("BANK SELECT FOR {}", window),
+                RegionKind::PageTable => "PAGE TABLE".to_string()
+            };
+
+            println!("{:#06X}-{:#06X} {} {}", region.start, region.end, kind, region.name);
+        }
+
+        println!("Unmapped addresses: RAM");
+    }
+}
+
+impl Default for SystemBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for SystemBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = match self.region_index(addr) {
+            Some(index) => {
+                let region = &mut self.regions[index];
+                let offset = addr - region.start;
+
+                match &mut region.kind {
+                    RegionKind::Rom(rom) => rom.data[offset as usize],
+                    RegionKind::Device(device) => device.read(offset),
+                    RegionKind::Banked(banked) => banked.data[banked_index(region.start, region.end, banked.bank, offset)],
+                    RegionKind::BankSelect(window) => {
+                        let window = window.clone();
+                        self.selected_bank(&window)
+                    },
+                    RegionKind::PageTable => self.permissions[offset as usize]
+                }
+            },
+            None => self.ram.read(addr)
+        };
+
+        self.record(addr, value, false);
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.record(addr, value, true);
+
+        match self.region_index(addr) {
+            Some(index) => {
+                let region = &mut self.regions[index];
+                let offset = addr - region.start;
+
+                match &mut region.kind {
+                    RegionKind::Rom(rom) => self.fault = rom_write(rom.policy, addr, value).or(self.fault),
+                    RegionKind::Device(device) => device.write(offset, value),
+                    RegionKind::Banked(banked) => match banked.policy {
+                        Some(policy) => self.fault = rom_write(policy, addr, value).or(self.fault),
+                        None => banked.data[banked_index(region.start, region.end, banked.bank, offset)] = value
+                    },
+                    RegionKind::BankSelect(window) => {
+                        let window = window.clone();
+                        let _ = self.select_bank(&window, value);
+                    },
+                    RegionKind::PageTable => self.permissions[offset as usize] = value
+                }
+            },
+            None => self.ram.write(addr, value)
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match self.region_index(addr) {
+            Some(index) => {
+                let region = &self.regions[index];
+                let offset = addr - region.start;
+
+                match &region.kind {
+                    RegionKind::Rom(rom) => rom.data[offset as usize],
+                    RegionKind::Device(device) => device.peek(offset),
+                    RegionKind::Banked(banked) => banked.data[banked_index(region.start, region.end, banked.bank, offset)],
+                    RegionKind::BankSelect(window) => self.selected_bank(window),
+                    RegionKind::PageTable => self.permissions[offset as usize]
+                }
+            },
+            None => self.ram.read(addr)
+        }
+    }
+}
+
+fn banked_index(start: u16, end: u16, bank: u8, offset: u16) -> usize {
+    (bank as usize) * ((end - start) as usize + 1) + offset as usize
+}
+
+fn rom_write(policy: WritePolicy, addr: u16, value: u8) -> Option<BusFault> {
+    match policy {
+        WritePolicy::Ignore => None,
+        WritePolicy::Log => {
+            println!("Ignored write of {:#04X} to ROM at {:#06X}", value, addr);
+            None
+        },
+        WritePolicy::Fault => Some(BusFault { addr, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(policy: WritePolicy) -> SystemBus {
+        let mut bus = SystemBus::new();
+        bus.write(0xF000, 0x11);
+        bus.map_rom("ROM", 0xF000, 0xF0FF, policy).unwrap();
+        bus
+    }
+
+    #[test]
+    fn rom_starts_with_the_ram_under_it() {
+        let mut bus = rom(WritePolicy::Ignore);
+
+        assert_eq!(bus.read(0xF000), 0x11);
+        assert_eq!(bus.peek(0xF001), 0x00);
+    }
+
+    #[test]
+    fn ignored_and_logged_rom_writes_change_nothing() {
+        for policy in [WritePolicy::Ignore, WritePolicy::Log] {
+            let mut bus = rom(policy);
+            bus.write(0xF000, 0x22);
+
+            assert_eq!(bus.peek(0xF000), 0x11);
+            assert_eq!(bus.ram.read(0xF000), 0x11);
+            assert!(bus.take_fault().is_none());
+        }
+    }
+
+    #[test]
+    fn faulting_rom_writes_report_a_fault() {
+        let mut bus = rom(WritePolicy::Fault);
+        bus.write(0xF010, 0x22);
+
+        let fault = bus.take_fault().unwrap();
+        assert_eq!((fault.addr, fault.value), (0xF010, 0x22));
+        assert_eq!(bus.peek(0xF010), 0x00);
+        assert!(bus.take_fault().is_none());
+    }
+
+    #[test]
+    fn burning_programs_rom_and_writes_ram_elsewhere() {
+        let mut bus = rom(WritePolicy::Fault);
+        bus.map_banked("BANK", 0x4000, 0x40FF, 2, 0xFF00, Some(WritePolicy::Fault)).unwrap();
+        bus.write(0xFF00, 1);
+
+        bus.burn(0xF000, 0x22);
+        bus.burn(0x4000, 0x33);
+        bus.burn(0x1000, 0x44);
+
+        assert_eq!(bus.peek(0xF000), 0x22);
+        assert_eq!((bus.peek_bank(0x4000, 0), bus.peek_bank(0x4000, 1)), (0x00, 0x33));
+        assert_eq!(bus.peek(0x1000), 0x44);
+        assert!(bus.take_fault().is_none());
+    }
+
+    #[test]
+    fn power_on_clears_ram_banks_and_permissions() {
+        let mut bus = rom(WritePolicy::Fault);
+        bus.map_banked("RAM", 0x4000, 0x40FF, 2, 0xFF00, None).unwrap();
+        bus.map_banked("BANKED_ROM", 0x5000, 0x50FF, 2, 0xFF01, Some(WritePolicy::Ignore)).unwrap();
+        bus.write(0x1000, 0x22);
+        bus.write(0xFF00, 1);
+        bus.write(0x4000, 0x33);
+        bus.burn(0x5000, 0x44);
+        bus.permissions[0x10] = 0;
+
+        bus.power_on();
+
+        assert_eq!(bus.peek(0x1000), 0x00);
+        assert_eq!((bus.peek(0xFF00), bus.peek_bank(0x4000, 1)), (0, 0x00));
+        assert_eq!((bus.peek(0xF000), bus.peek(0x5000)), (0x11, 0x44));
+        assert!(bus.allowed(0x1000, PAGE_READ));
+    }
+
+    #[test]
+    fn backwards_banked_windows_are_rejected() {
+        let mut bus = SystemBus::new();
+
+        assert_eq!(bus.map_banked("BANK", 0x8000, 0x4000, 256, 0xFF00, None), Err(String::from("Region BANK ends before it starts")));
+        assert!(bus.regions.is_empty());
+    }
+
+    #[test]
+    fn unmapping_a_banked_window_removes_its_select_register() {
+        let mut bus = SystemBus::new();
+        bus.map_banked("BANK", 0x4000, 0x7FFF, 4, 0xFF00, None).unwrap();
+
+        assert!(bus.unmap("BANK_SELECT").is_err());
+
+        bus.unmap("BANK").unwrap();
+
+        assert!(bus.regions.is_empty());
+        assert!(bus.map_banked("BANK", 0x4000, 0x7FFF, 4, 0xFF00, None).is_ok());
+    }
+}
@@ -1,9 +1,15 @@
-use std::u8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::bus::{Bus, BusFault, SystemBus, PAGE_EXECUTE, PAGE_READ, PAGE_WRITE};
+use crate::debugger::{Debugger, WatchHit};
+use crate::microcode::*;
+use crate::trace::VcdTrace;
 
 pub const GENERAL_REGISTER_NAMES: &[&str] = &["A", "B", "C", "D", "L", "H"]; 
 pub const SPECIAL_REGISTER_NAMES: &[&str] = &["PC", "SP", "F", "S"]; 
-const FLAG_NAMES: &[&str] = &["ZERO", "CARRY"]; 
-const STATUS_NAMES: &[&str] = &["HALT"]; 
+pub const FLAG_NAMES: &[&str] = &["ZERO", "CARRY"]; 
+const STATUS_NAMES: &[&str] = &["HALT", "INTERRUPT_ENABLE", "SUPERVISOR"]; 
 
 const I_NOP: u8 = 0x0;
 const I_MOV: u8 = 0x1;
@@ -22,10 +28,55 @@ const I_NAND: u8 = 0xD;
 const I_NOR: u8 = 0xE;
 const I_HLT: u8 = 0xF;
 
+const X_LDR_OFFSET: u8 = 0x0;
+const X_STR_OFFSET: u8 = 0x1;
+const X_LDR_INC: u8 = 0x2;
+const X_STR_INC: u8 = 0x3;
+const X_LDR_DEC: u8 = 0x4;
+const X_STR_DEC: u8 = 0x5;
+const X_EI: u8 = 0x6;
+const X_DI: u8 = 0x7;
+const X_RETI: u8 = 0x8;
+const X_SYSCALL: u8 = 0x9;
+
 const F_ZERO: u16 = 0x00;
 const F_CARRY: u16 = 0x01;
 
 const S_HALT: u16 = 0x00;
+const S_INTERRUPT_ENABLE: u16 = 0x01;
+const S_SUPERVISOR: u16 = 0x02;
+
+pub const TRAP_READ: u8 = 0x1;
+pub const TRAP_WRITE: u8 = 0x2;
+pub const TRAP_EXECUTE: u8 = 0x3;
+pub const TRAP_PRIVILEGED: u8 = 0x4;
+pub const TRAP_SYSCALL: u8 = 0x5;
+
+// Instructions take one cycle per microcode step (instruction_cycles).
+// Interrupt entry is hardwired: four pushes, two vector reads and one cycle
+// to switch. An instruction that traps, SYSCALL included, costs TRAP_CYCLES
+// in all, however far it got.
+pub const INTERRUPT_CYCLES: u64 = 7;
+pub const TRAP_CYCLES: u64 = 8;
+pub const IDLE_CYCLES: u64 = 1;
+
+const MAX_LAG: Duration = Duration::from_millis(100);
+const MAX_SLEEP: Duration = Duration::from_millis(50);
+const UNTHROTTLED_BATCH: u64 = 10000;
+
+pub const TRAP_VECTOR: u16 = 0xFFF6;
+pub const STACK_VECTOR: u16 = 0xFFF8;
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum StopReason {
+    Halted,
+    Interrupted,
+    Breakpoint(usize),
+    Watchpoint(WatchHit)
+}
 
 #[derive(Copy, Clone)]
 pub struct GeneralRegister {
@@ -37,6 +88,8 @@ pub struct SpecialRegister {
     pub value: u16
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone)]
 pub struct CPU {
     pub general_registers: [GeneralRegister; 6],
     pub special_registers: [SpecialRegister; 4]
@@ -50,226 +103,681 @@ impl CPU {
         }
     }
 
+    // Register fields 6 and 7 name no register: they read as 0 and writes
+    // to them are dropped.
+    pub fn register(&self, index: usize) -> u8 {
+        self.general_registers.get(index).map_or(0, |register| register.value)
+    }
+
+    pub fn set_register(&mut self, index: usize, value: u8) {
+        if let Some(register) = self.general_registers.get_mut(index) {
+            register.value = value;
+        }
+    }
+
     pub fn hl(&self) -> u16 {
         (self.general_registers[4].value as u16) | ((self.general_registers[5].value as u16) << 0x8)
     }
+
+    pub fn set_hl(&mut self, value: u16) {
+        self.general_registers[4].value = (value & 0xFF) as u8;
+        self.general_registers[5].value = (value >> 0x8) as u8;
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub struct Memory {
-    pub memory: [u8; 0xFFFF]
+// Paces a run at `speed` Hz. Steps are batched into roughly a millisecond of
+// emulated time and after each batch the run waits until the wall-clock time
+// those cycles are due, measured from the start of the run so that sleep
+// overshoot is paid back instead of accumulating. If the host falls more than
+// MAX_LAG behind, the schedule restarts rather than bursting to catch up.
+pub struct Throttle {
+    speed: Option<u64>,
+    start: Instant,
+    start_cycles: u64
 }
 
-impl Memory {
-    pub fn new() -> Memory {
-        Memory {
-            memory: [0; 0xFFFF],
+impl Throttle {
+    pub fn new(speed: Option<u64>, cycles: u64) -> Throttle {
+        Throttle {
+            speed: speed.filter(|&speed| speed > 0),
+            start: Instant::now(),
+            start_cycles: cycles
         }
     }
 
-    pub fn read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    pub fn batch(&self) -> u64 {
+        self.speed.map_or(UNTHROTTLED_BATCH, |speed| (speed / 1000).max(1))
     }
 
-    pub fn write(&mut self, addr: u16, value: u8) {
-        self.memory[addr as usize] = value;
+    // How long to wait before running on from `cycles`. Capped so a stop
+    // request is seen promptly even at a few Hz.
+    pub fn delay(&mut self, cycles: u64) -> Duration {
+        let speed = match self.speed {
+            Some(speed) => speed,
+            None => return Duration::ZERO
+        };
+
+        // The counter goes backwards when the machine is powered on again
+        // mid-run, so the schedule starts over from there.
+        if cycles < self.start_cycles {
+            self.start = Instant::now();
+            self.start_cycles = cycles;
+        }
+
+        let due = Duration::from_nanos(((cycles - self.start_cycles) as u128 * 1_000_000_000 / speed as u128) as u64);
+        let elapsed = self.start.elapsed();
+
+        if due > elapsed {
+            return (due - elapsed).min(MAX_SLEEP);
+        }
+
+        if elapsed - due > MAX_LAG {
+            self.start = Instant::now();
+            self.start_cycles = cycles;
+        }
+
+        Duration::ZERO
     }
 }
 
 pub struct Computer {
     pub cpu: CPU,
-    pub memory: Memory
+    pub bus: SystemBus,
+    pub fault: Option<BusFault>,
+    pub cycles: u64,
+    pub microcode: bool,
+    pub micro: MicroState,
+    pub debugger: Debugger,
+    micro_saved: CPU,
+    breakpoints_armed: bool,
+    instruction_pc: u16,
+    watch_hit: Option<WatchHit>,
+    trace: Option<VcdTrace>,
+    irq_pending: bool,
+    nmi_pending: bool,
+    trap: Option<u8>
 }
 
 impl Computer {
     pub fn new() -> Computer {
-        Computer {
+        let mut computer = Computer {
             cpu: CPU::new(),
-            memory: Memory::new()
-        }
+            bus: SystemBus::new(),
+            fault: None,
+            cycles: 0,
+            microcode: false,
+            micro: MicroState::default(),
+            debugger: Debugger::new(),
+            micro_saved: CPU::new(),
+            breakpoints_armed: false,
+            instruction_pc: 0,
+            watch_hit: None,
+            trace: None,
+            irq_pending: false,
+            nmi_pending: false,
+            trap: None
+        };
+
+        computer.power_on();
+        computer
     }
 
-    pub fn run(&mut self, speed: u64) {
-        self.cpu.special_registers[3].value &= !(1 << S_HALT);
+    pub fn power_on(&mut self) {
+        self.bus.power_on();
+        self.cycles = 0;
+        self.reset();
+    }
+
+    // Clears every register, flag and pending interrupt, leaving the CPU
+    // running in the documented reset state: not halted, interrupts masked,
+    // supervisor mode. PC and SP are then read from the reset and stack
+    // vectors; a blank stack vector puts the stack directly below the vector
+    // table.
+    pub fn reset(&mut self) {
+        self.cpu = CPU::new();
+        self.cpu.special_registers[3].value = 1 << S_SUPERVISOR;
+        self.micro = MicroState::default();
+        self.irq_pending = false;
+        self.nmi_pending = false;
+        self.trap = None;
+
+        self.cpu.special_registers[0].value = self.read_vector(RESET_VECTOR);
+        self.cpu.special_registers[1].value = match self.read_vector(STACK_VECTOR) {
+            0 => TRAP_VECTOR,
+            address => address
+        };
+    }
 
-        let ms_per_cycle = 1000 / speed;
-        let mut last_step = std::time::Instant::now();
+    // Requests are latched until the CPU accepts them, so they can be raised
+    // at any point between steps. Device IRQ lines are levels instead, seen
+    // only while the device holds them.
+    pub fn raise_irq(&mut self) {
+        self.irq_pending = true;
+    }
+
+    pub fn raise_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    // Runs until HLT, or until `stop` is set, which is checked between
+    // instructions. Without a speed (or with 0) it runs as fast as the host
+    // allows; with a speed it is paced by a Throttle.
+    pub fn run(&mut self, speed: Option<u64>, stop: &AtomicBool) -> StopReason {
+        let mut throttle = self.start_run(speed);
 
         loop {
-            let now = std::time::Instant::now();
+            if stop.load(Ordering::Relaxed) {
+                return StopReason::Interrupted;
+            }
+
+            let delay = throttle.delay(self.cycles);
 
-            if (now - last_step).as_millis() > ms_per_cycle as u128 {
-                self.step();
+            if !delay.is_zero() {
+                std::thread::sleep(delay);
+                continue;
+            }
 
-                last_step = now; // Does this render 'now' invalid (ownership) (need to clone?)?
+            if let Some(reason) = self.run_batch(throttle.batch(), stop) {
+                return reason;
             }
+        }
+    }
+
+    // Wakes the CPU and starts the schedule for a run at `speed`. A
+    // breakpoint at the current PC is passed over, so a run can continue
+    // from where one stopped.
+    pub fn start_run(&mut self, speed: Option<u64>) -> Throttle {
+        self.cpu.special_registers[3].value &= !(1 << S_HALT);
+        self.breakpoints_armed = false;
+        self.watch_hit = None;
+
+        Throttle::new(speed, self.cycles)
+    }
 
+    // Runs whole instructions for at least `cycles` cycles, returning early
+    // if the CPU halts, `stop` is set or a breakpoint is reached before its
+    // instruction is fetched.
+    pub fn run_batch(&mut self, cycles: u64, stop: &AtomicBool) -> Option<StopReason> {
+        let mut ran = 0;
+
+        while ran < cycles {
             if self.halted() {
-                break;
+                return Some(StopReason::Halted);
+            }
+
+            if stop.load(Ordering::Relaxed) {
+                return Some(StopReason::Interrupted);
+            }
+
+            if self.breakpoints_armed {
+                if let Some(id) = self.debugger.check(self.cpu.special_registers[0].value, &self.cpu, &self.bus) {
+                    return Some(StopReason::Breakpoint(id));
+                }
+            }
+
+            ran += self.step();
+            self.breakpoints_armed = true;
+
+            if let Some(hit) = self.watch_hit.take() {
+                return Some(StopReason::Watchpoint(hit));
+            }
+        }
+
+        if self.halted() {
+            return Some(StopReason::Halted);
+        }
+
+        None
+    }
+
+    // Returns the cycles the step took and adds them to the running counter.
+    // Devices are clocked by the same amount. A step where a device masters
+    // the bus is spent stalled instead. In microcode mode a step clocks the
+    // machine to the next instruction boundary.
+    pub fn step(&mut self) -> u64 {
+        if self.microcode {
+            let mut cycles = self.pulse().cycles();
+
+            while !self.micro.at_boundary() {
+                cycles += self.pulse().cycles();
+            }
+
+            return cycles;
+        }
+
+        let cycles = match self.bus.run_bus_masters() {
+            0 => self.step_cpu(),
+            stall => stall
+        };
+
+        self.record_trace(cycles);
+        self.cycles += cycles;
+        self.bus.tick(cycles);
+
+        cycles
+    }
+
+    // Writes a VCD waveform of the registers and bus activity, one time unit
+    // per cycle, until stop_trace().
+    pub fn start_trace(&mut self, path: &str) -> std::io::Result<()> {
+        let mut trace = VcdTrace::create(path)?;
+        trace.record(self.cycles, 1, &self.cpu, &[])?;
+
+        self.trace = Some(trace);
+        self.bus.set_recording(true);
+
+        Ok(())
+    }
+
+    pub fn stop_trace(&mut self) -> std::io::Result<()> {
+        self.bus.set_recording(false);
+
+        match self.trace.take() {
+            Some(trace) => trace.finish().map(|_| ()),
+            None => Ok(())
+        }
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    fn record_trace(&mut self, cycles: u64) {
+        if let Some(trace) = &mut self.trace {
+            let accesses = self.bus.take_accesses();
+
+            if let Err(error) = trace.record(self.cycles, cycles, &self.cpu, &accesses) {
+                println!("Trace stopped: {}", error);
+                self.trace = None;
+                self.bus.set_recording(false);
             }
-            
-            let time_to_sleep = std::time::Duration::from_millis(ms_per_cycle - now.elapsed().as_millis() as u64);
-            std::thread::sleep(time_to_sleep);
         }
     }
 
-    pub fn step(&mut self) {
+    fn step_cpu(&mut self) -> u64 {
+        self.instruction_pc = self.cpu.special_registers[0].value;
+
+        if self.accept_interrupt() {
+            return INTERRUPT_CYCLES;
+        }
+
         if self.halted() {
+            return IDLE_CYCLES;
+        }
+
+        // Restored if the instruction traps, so the handler sees the state
+        // from before the faulting instruction and can restart it.
+        let saved = self.cpu;
+
+        let instruction = self.fetch_byte();
+
+        let cycles = if self.trap.is_none() {
+            self.execute_instruction(instruction)
+        } else {
+            0
+        };
+
+        if self.finish_instruction(saved) {
+            return TRAP_CYCLES;
+        }
+
+        cycles
+    }
+
+    // Takes a pending NMI, or an IRQ if enabled. Only called at instruction
+    // boundaries.
+    fn accept_interrupt(&mut self) -> bool {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.enter_interrupt(NMI_VECTOR);
+            return true;
+        }
+
+        if (self.irq_pending || self.bus.irq()) && self.interrupts_enabled() {
+            self.irq_pending = false;
+            self.enter_interrupt(IRQ_VECTOR);
+            return true;
+        }
+
+        false
+    }
+
+    // Enters the trap handler if the instruction trapped, restoring the state
+    // from before it, and halts on a bus fault. Returns whether it trapped.
+    // SYSCALL completes before trapping, so its handler returns past it.
+    fn finish_instruction(&mut self, saved: CPU) -> bool {
+        let trapped = match self.trap.take() {
+            Some(cause) => {
+                if cause != TRAP_SYSCALL {
+                    self.cpu = saved;
+                }
+
+                self.enter_trap(cause);
+                true
+            },
+            None => false
+        };
+
+        if let Some(fault) = self.bus.take_fault() {
+            self.fault = Some(fault);
+            self.cpu.special_registers[3].value |= 1 << S_HALT;
+        }
+
+        trapped
+    }
+
+    // One clock pulse of the microcoded machine. Interrupts, bus masters and
+    // the halt state are handled at instruction boundaries as in
+    // instruction mode, each taking a single pulse (or the DMA stall).
+    pub fn pulse(&mut self) -> Pulse {
+        let pulse = self.pulse_cpu();
+        let cycles = pulse.cycles();
+
+        self.record_trace(cycles);
+        self.cycles += cycles;
+        self.bus.tick(cycles);
+
+        pulse
+    }
+
+    fn pulse_cpu(&mut self) -> Pulse {
+        if self.micro.at_boundary() {
+            self.instruction_pc = self.cpu.special_registers[0].value;
+
+            let stall = self.bus.run_bus_masters();
+
+            if stall > 0 {
+                return Pulse::Stall(stall);
+            }
+
+            if self.accept_interrupt() {
+                return Pulse::Interrupt;
+            }
+
+            if self.halted() {
+                return Pulse::Idle;
+            }
+
+            self.micro_saved = self.cpu;
+        }
+
+        let step = self.micro.step;
+        let page = self.micro.page;
+        let flags = self.cpu.special_registers[2].value as u8;
+        let word = microcode_rom()[rom_address(page, flags, self.micro.ir, step)];
+
+        self.execute_control_word(word);
+
+        if word & PG != 0 && self.trap.is_none() {
+            self.micro.page = true;
+            self.micro.step = 0;
+        } else if word & SR != 0 || self.trap.is_some() || step as usize == MAX_STEPS - 1 {
+            self.micro.page = false;
+            self.micro.step = 0;
+
+            // The pulses already run count towards the trap, including the
+            // prefix of an extended instruction.
+            if self.finish_instruction(self.micro_saved) {
+                let prefix = if page { instruction_cycles(false, flags, 0x08) } else { 0 };
+                return Pulse::Trap(TRAP_CYCLES.saturating_sub(prefix + step as u64));
+            }
+        } else {
+            self.micro.step += 1;
+        }
+
+        Pulse::Control { ir: self.micro.ir, page, step, word }
+    }
+
+    // Outputs drive the bus first, then every input latches the bus value
+    // and the counters advance. SPD is applied before the address is taken so
+    // a push needs a single step.
+    fn execute_control_word(&mut self, word: u32) {
+        if word & PRIV != 0 && !self.supervisor() {
+            self.trap = Some(TRAP_PRIVILEGED);
             return;
         }
 
-        let instruction = self.memory.read(self.cpu.special_registers[0].value);
-        self.increment_pc();
-        self.execute_instruction(instruction);
+        if word & SPD != 0 {
+            self.decrement_sp();
+        }
+
+        let address = match word & ADDR_MASK {
+            ADDR_PC => self.cpu.special_registers[0].value,
+            ADDR_SP => self.cpu.special_registers[1].value,
+            ADDR_HL => self.cpu.hl(),
+            _ => self.micro.mar
+        };
+
+        let rd = (self.micro.ir & 0x7) as usize;
+        let rs = (self.micro.or & 0x7) as usize;
+        let tmp = self.micro.tmp;
+        let mar = self.micro.mar;
+        let hl = self.cpu.hl();
+        let carry = self.cpu.special_registers[2].value & (1 << F_CARRY) != 0;
+        let (result, carry, zero) = alu(word & ALU_MASK, self.cpu.register(rd), tmp, carry);
+
+        let bus = match word & OUT_MASK {
+            OUT_RAM if word & ADDR_MASK == ADDR_PC => self.access_memory(address, PAGE_EXECUTE, TRAP_EXECUTE),
+            OUT_RAM => self.read_memory(address),
+            OUT_TMP => tmp,
+            OUT_RD => self.cpu.register(rd),
+            OUT_RS => self.cpu.register(rs),
+            OUT_ALU => result,
+            OUT_OR => self.micro.or,
+            _ => 0
+        };
+
+        if self.trap.is_some() {
+            return;
+        }
+
+        if word & RI != 0 {
+            self.write_memory(address, bus);
+        }
+
+        if word & II != 0 {
+            self.micro.ir = bus;
+        }
+
+        if word & OI != 0 {
+            self.micro.or = bus;
+        }
+
+        if word & TI != 0 {
+            self.micro.tmp = bus;
+        }
+
+        if word & RDI != 0 {
+            self.cpu.set_register(rd, bus);
+        }
+
+        // CMP only drives ZERO and the adds only CARRY, matching the
+        // instruction-level ALU.
+        if word & FI != 0 {
+            let (flag, set) = match word & ALU_MASK {
+                ALU_CMP => (F_ZERO, zero),
+                _ => (F_CARRY, carry)
+            };
+
+            if set {
+                self.cpu.special_registers[2].value |= 1 << flag;
+            } else {
+                self.cpu.special_registers[2].value &= !(1 << flag);
+            }
+        }
+
+        if word & MW != 0 {
+            self.micro.mar = ((bus as u16) << 0x8) | tmp as u16;
+        }
+
+        if word & MHL != 0 {
+            self.micro.mar = self.cpu.hl();
+        }
+
+        if word & HLM != 0 {
+            self.cpu.set_hl(mar);
+        }
+
+        if word & MADD != 0 {
+            self.micro.mar = mar.wrapping_add(tmp as u16);
+        }
+
+        if word & JR != 0 {
+            self.cpu.special_registers[0].value = self.cpu.special_registers[0].value.wrapping_add(mar);
+        }
+
+        if word & J != 0 {
+            self.cpu.special_registers[0].value = mar;
+        }
+
+        if word & SI != 0 {
+            self.cpu.special_registers[3].value = bus as u16;
+        }
+
+        if word & FBI != 0 {
+            self.cpu.special_registers[2].value = bus as u16;
+        }
+
+        if word & IEL != 0 {
+            if self.micro.ir & 0x10 == 0 {
+                self.cpu.special_registers[3].value |= 1 << S_INTERRUPT_ENABLE;
+            } else {
+                self.cpu.special_registers[3].value &= !(1 << S_INTERRUPT_ENABLE);
+            }
+        }
+
+        if word & HLT != 0 {
+            self.cpu.special_registers[3].value |= 1 << S_HALT;
+        }
+
+        if word & SYS != 0 {
+            self.trap = Some(TRAP_SYSCALL);
+        }
+
+        if word & CE != 0 {
+            self.cpu.special_registers[0].value = self.cpu.special_registers[0].value.wrapping_add(1);
+        }
+
+        if word & SPI != 0 {
+            self.increment_sp();
+        }
+
+        // Counts from HL as it was before the step, so the count wins over a
+        // load into L or H like it does in instruction mode.
+        if word & HLC != 0 {
+            if self.micro.ir & 0x40 == 0 {
+                self.cpu.set_hl(hl.wrapping_add(1));
+            } else {
+                self.cpu.set_hl(hl.wrapping_sub(1));
+            }
+        }
     }
 
-    pub fn execute_instruction(&mut self, instruction: u8) {
+    // Returns the cycles the instruction took.
+    pub fn execute_instruction(&mut self, instruction: u8) -> u64 {
         let opcode = self.get_opcode(&instruction);
+        let register = (instruction & 0x7) as usize;
+        let cycles = instruction_cycles(false, self.cpu.special_registers[2].value as u8, instruction);
 
         match opcode {
-            I_NOP => {
-                return;
+            I_NOP if instruction & 0x8 != 0 => {
+                let extended_instruction = self.fetch_byte();
+                return cycles + self.execute_extended_instruction(extended_instruction);
             },
+            I_NOP => {},
             I_MOV => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
+                let operand = self.fetch_byte();
 
                 if instruction & 0x8 != 0 { 
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value = self.cpu.general_registers[(operand & 0x7) as usize].value;
+                    self.cpu.set_register(register, self.cpu.register((operand & 0x7) as usize));
                 } else {
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value = operand;
+                    self.cpu.set_register(register, operand);
                 }
             },
             I_LDR => {
-                let address: u16;
-
-                if instruction & 0x8 != 0 {
-                    address = self.cpu.hl();
+                let address = if instruction & 0x8 != 0 {
+                    self.cpu.hl()
                 } else {
-                    let low_byte = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                    let high_byte = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                    address = (low_byte as u16) | ((high_byte as u16) << 0x8);
-                }
+                    self.fetch_word()
+                };
 
-                self.cpu.general_registers[(instruction & 0x7) as usize].value = self.memory.read(address);
+                let value = self.read_memory(address);
+                self.cpu.set_register(register, value);
             },
             I_STR => {
-                let address: u16;
-
-                if instruction & 0x8 != 0 {
-                    address = self.cpu.hl();
+                let address = if instruction & 0x8 != 0 {
+                    self.cpu.hl()
                 } else {
-                    let low_byte = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                    let high_byte = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                    address = (low_byte as u16) | ((high_byte as u16) << 0x8);
-                }
+                    self.fetch_word()
+                };
 
-                self.memory.write(address, self.cpu.general_registers[(instruction & 0x7) as usize].value);
+                self.write_memory(address, self.cpu.register(register));
             },
             I_LHL => {
-                let low_byte = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
-                let high_byte = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
+                let address = self.fetch_word();
 
-                self.cpu.general_registers[4].value = low_byte;
-                self.cpu.general_registers[5].value = high_byte;
+                self.cpu.set_hl(address);
             },
             I_PUSH => {
-                let value: u8; 
-
-                if instruction & 0x8 != 0 { 
-                    value = self.cpu.general_registers[(instruction & 0x7) as usize].value;
+                let value = if instruction & 0x8 != 0 { 
+                    self.cpu.register(register)
                 } else {
-                    value = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                }
+                    self.fetch_byte()
+                };
 
-                self.decrement_sp();
-                self.memory.write(self.cpu.special_registers[1].value, value);
+                self.push(value);
             },
             I_POP => {
-                self.cpu.general_registers[(instruction & 0x7) as usize].value = self.memory.read(self.cpu.special_registers[1].value);
-                self.increment_sp();
+                let value = self.pop();
+                self.cpu.set_register(register, value);
             },
             I_JMP => {
-                let address: i16;
-
-                if instruction & 0x8 != 0 {
-                    address = self.cpu.hl() as i16;
+                let address = if instruction & 0x8 != 0 {
+                    self.cpu.hl() as i16
                 } else {
-                    let low_byte = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                    let high_byte = self.memory.read(self.cpu.special_registers[0].value);
-                    self.increment_pc();
-                    address = (low_byte as i16) | ((high_byte as i16) << 0x8);
-                }
+                    self.fetch_word() as i16
+                };
 
-                if address > 0 {
-                    self.cpu.special_registers[0].value += address.abs() as u16;
-                } else {
-                    self.cpu.special_registers[0].value -= address.abs() as u16;
-                }
+                self.jump_relative(address);
             },
             I_JZ => {
+                let address = if instruction & 0x8 != 0 {
+                    self.cpu.hl() as i16
+                } else {
+                    self.fetch_word() as i16
+                };
+
                 if self.cpu.special_registers[2].value & (1 << F_ZERO) == 0 {
-                    let address: i16;
-
-                    if instruction & 0x8 != 0 {
-                        address = self.cpu.hl() as i16;
-                    } else {
-                        let low_byte = self.memory.read(self.cpu.special_registers[0].value);
-                        self.increment_pc();
-                        let high_byte = self.memory.read(self.cpu.special_registers[0].value);
-                        self.increment_pc();
-                        address = (low_byte as i16) | ((high_byte as i16) << 0x8);
-                    }
-
-                    if address > 0 {
-                        self.cpu.special_registers[0].value += address.abs() as u16;
-                    } else {
-                        self.cpu.special_registers[0].value -= address.abs() as u16;
-                    }
+                    self.jump_relative(address);
                 }
             },
             I_ADD => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
-                let result;
+                let operand = self.fetch_operand(instruction);
+                let result = self.cpu.register(register).overflowing_add(operand);
 
-                if instruction & 0x8 != 0 { 
-                    result = self.cpu.general_registers[(instruction & 0x7) as usize].value.overflowing_add(self.cpu.general_registers[(operand & 0x7) as usize].value);
-                } else {
-                    result = self.cpu.general_registers[(instruction & 0x7) as usize].value.overflowing_add(operand);
-                }
-
-                self.cpu.general_registers[(instruction & 0x7) as usize].value = result.0;
+                self.cpu.set_register(register, result.0);
 
                 if result.1 {
-                    self.cpu.special_registers[2].value |= 1 << 1;
+                    self.cpu.special_registers[2].value |= 1 << F_CARRY;
                 } else {
-                    self.cpu.special_registers[2].value &= !(1 << 1);
+                    self.cpu.special_registers[2].value &= !(1 << F_CARRY);
                 }
             },
             I_ADC => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
-                let mut result;
-
-                if instruction & 0x8 != 0 { 
-                    result = self.cpu.general_registers[(instruction & 0x7) as usize].value.overflowing_add(self.cpu.general_registers[(operand & 0x7) as usize].value);
-                } else {
-                    result = self.cpu.general_registers[(instruction & 0x7) as usize].value.overflowing_add(operand);
-                }
+                let operand = self.fetch_operand(instruction);
+                let mut result = self.cpu.register(register).overflowing_add(operand);
                 
-                if self.cpu.special_registers[2].value & (1 << 1) != 0 {
+                if self.cpu.special_registers[2].value & (1 << F_CARRY) != 0 {
                     result = result.0.overflowing_add(1);
                 } 
 
-                self.cpu.general_registers[(instruction & 0x7) as usize].value = result.0;
+                self.cpu.set_register(register, result.0);
 
                 if result.1 {
                     self.cpu.special_registers[2].value |= 1 << F_CARRY;
@@ -278,121 +786,842 @@ impl Computer {
                 }
             }, 
             I_CMP => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
-
-                let comparison;
+                let operand = self.fetch_operand(instruction);
 
-                if instruction & 0x8 != 0 { 
-                    comparison = self.cpu.general_registers[(instruction & 0x7) as usize].value - self.cpu.general_registers[(operand & 0x7) as usize].value;
-                } else {
-                    comparison = self.cpu.general_registers[(instruction & 0x7) as usize].value - operand;
-                }
-
-                if comparison != 0 {
+                if self.cpu.register(register).wrapping_sub(operand) != 0 {
                     self.cpu.special_registers[2].value &= !(1 << F_ZERO);
                 } else {
                     self.cpu.special_registers[2].value |= 1 << F_ZERO;
                 }
             },
             I_SUB => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
+                let operand = self.fetch_operand(instruction);
 
-                if instruction & 0x8 != 0 { 
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value -= self.cpu.general_registers[(operand & 0x7) as usize].value;
-                } else {
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value -= operand;
-                }
+                self.cpu.set_register(register, self.cpu.register(register).wrapping_sub(operand));
             },
             I_NAND => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
+                let operand = self.fetch_operand(instruction);
 
-                if instruction & 0x8 != 0 { 
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value &= self.cpu.general_registers[(operand & 0x7) as usize].value;
-                } else {
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value &= operand;
-                }
-
-                self.cpu.general_registers[(instruction & 0x7) as usize].value = !self.cpu.general_registers[(instruction & 0x7) as usize].value;
+                self.cpu.set_register(register, !(self.cpu.register(register) & operand));
             },
             I_NOR => {
-                let operand = self.memory.read(self.cpu.special_registers[0].value);
-                self.increment_pc();
-
-                if instruction & 0x8 != 0 { 
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value |= self.cpu.general_registers[(operand & 0x7) as usize].value;
-                } else {
-                    self.cpu.general_registers[(instruction & 0x7) as usize].value |= operand;
-                }
+                let operand = self.fetch_operand(instruction);
 
-                self.cpu.general_registers[(instruction & 0x7) as usize].value = !self.cpu.general_registers[(instruction & 0x7) as usize].value;
+                self.cpu.set_register(register, !(self.cpu.register(register) | operand));
+            },
+            I_HLT if !self.supervisor() => {
+                self.trap = Some(TRAP_PRIVILEGED);
             },
             I_HLT => {
                 self.cpu.special_registers[3].value |= 1 << S_HALT;
             }
-            _ => {
-                return;
-            }
+            _ => {}
+        }
+
+        cycles
+    }
+
+    // Extended page: 0x08-0x0F prefix, followed by an instruction byte with
+    // the same layout (opcode in the high nibble, register in the low bits).
+    pub fn execute_extended_instruction(&mut self, instruction: u8) -> u64 {
+        let opcode = self.get_opcode(&instruction);
+        let register = (instruction & 0x7) as usize;
+        let cycles = instruction_cycles(true, self.cpu.special_registers[2].value as u8, instruction);
+
+        match opcode {
+            X_LDR_OFFSET => {
+                let offset = self.fetch_byte();
+                let address = self.cpu.hl().wrapping_add(offset as u16);
+
+                let value = self.read_memory(address);
+                self.cpu.set_register(register, value);
+            },
+            X_STR_OFFSET => {
+                let offset = self.fetch_byte();
+                let address = self.cpu.hl().wrapping_add(offset as u16);
+
+                self.write_memory(address, self.cpu.register(register));
+            },
+            X_LDR_INC => {
+                let address = self.cpu.hl();
+
+                let value = self.read_memory(address);
+                self.cpu.set_register(register, value);
+                self.cpu.set_hl(address.wrapping_add(1));
+            },
+            X_STR_INC => {
+                let address = self.cpu.hl();
+
+                self.write_memory(address, self.cpu.register(register));
+                self.cpu.set_hl(address.wrapping_add(1));
+            },
+            X_LDR_DEC => {
+                let address = self.cpu.hl();
+
+                let value = self.read_memory(address);
+                self.cpu.set_register(register, value);
+                self.cpu.set_hl(address.wrapping_sub(1));
+            },
+            X_STR_DEC => {
+                let address = self.cpu.hl();
+
+                self.write_memory(address, self.cpu.register(register));
+                self.cpu.set_hl(address.wrapping_sub(1));
+            },
+            X_EI | X_DI | X_RETI if !self.supervisor() => {
+                self.trap = Some(TRAP_PRIVILEGED);
+            },
+            X_EI => {
+                self.cpu.special_registers[3].value |= 1 << S_INTERRUPT_ENABLE;
+            },
+            X_DI => {
+                self.cpu.special_registers[3].value &= !(1 << S_INTERRUPT_ENABLE);
+            },
+            X_RETI => {
+                // S is applied last so the whole frame is popped with
+                // supervisor permissions, even when returning to user mode.
+                let status = self.pop();
+                self.cpu.special_registers[2].value = self.pop() as u16;
+                let low_byte = self.pop();
+                let high_byte = self.pop();
+                self.cpu.special_registers[0].value = (low_byte as u16) | ((high_byte as u16) << 0x8);
+                self.cpu.special_registers[3].value = status as u16;
+            },
+            X_SYSCALL => {
+                self.trap = Some(TRAP_SYSCALL);
+            },
+            _ => {}
+        }
+
+        cycles
+    }
+
+    fn fetch_byte(&mut self) -> u8 {
+        let value = self.access_memory(self.cpu.special_registers[0].value, PAGE_EXECUTE, TRAP_EXECUTE);
+        self.increment_pc();
+
+        value
+    }
+
+    fn read_memory(&mut self, address: u16) -> u8 {
+        self.access_memory(address, PAGE_READ, TRAP_READ)
+    }
+
+    fn access_memory(&mut self, address: u16, permission: u8, cause: u8) -> u8 {
+        if !self.allowed(address, permission) {
+            self.trap = self.trap.or(Some(cause));
+            return 0;
+        }
+
+        let value = self.bus.read(address);
+        self.watch(address, value, value, false);
+
+        value
+    }
+
+    fn write_memory(&mut self, address: u16, value: u8) {
+        if !self.allowed(address, PAGE_WRITE) {
+            self.trap = self.trap.or(Some(TRAP_WRITE));
+            return;
+        }
+
+        let old = if self.debugger.watching() { self.bus.peek(address) } else { 0 };
+
+        self.bus.write(address, value);
+        self.watch(address, old, value, true);
+    }
+
+    // Keeps the first watched access of the instruction for run_batch() or
+    // the REPL to report.
+    fn watch(&mut self, address: u16, old: u8, new: u8, write: bool) {
+        if !self.debugger.watching() || self.watch_hit.is_some() {
+            return;
+        }
+
+        if let Some(id) = self.debugger.check_access(address, write) {
+            self.watch_hit = Some(WatchHit { id, address, pc: self.instruction_pc, old, new, write });
         }
     }
 
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    // Page permissions only restrict user mode, and nothing more is touched
+    // once an instruction has trapped.
+    fn allowed(&self, address: u16, permission: u8) -> bool {
+        self.trap.is_none() && (self.supervisor() || self.bus.allowed(address, permission))
+    }
+
+    // The ALU operand: the next byte, or in mode 1 the register it names.
+    fn fetch_operand(&mut self, instruction: u8) -> u8 {
+        let operand = self.fetch_byte();
+
+        if instruction & 0x8 != 0 {
+            self.cpu.register((operand & 0x7) as usize)
+        } else {
+            operand
+        }
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low_byte = self.fetch_byte();
+        let high_byte = self.fetch_byte();
+
+        (low_byte as u16) | ((high_byte as u16) << 0x8)
+    }
+
+    fn push(&mut self, value: u8) {
+        self.decrement_sp();
+        self.write_memory(self.cpu.special_registers[1].value, value);
+    }
+
+    fn pop(&mut self) -> u8 {
+        let value = self.read_memory(self.cpu.special_registers[1].value);
+        self.increment_sp();
+
+        value
+    }
+
+    // Saves PC (high byte first), F and S, then switches to supervisor mode,
+    // masks further interrupts and jumps through the vector. RETI undoes this
+    // in reverse, returning to the interrupted mode.
+    fn enter_interrupt(&mut self, vector: u16) {
+        self.cpu.special_registers[3].value &= !(1 << S_HALT);
+
+        let status = self.cpu.special_registers[3].value;
+        self.cpu.special_registers[3].value |= 1 << S_SUPERVISOR;
+
+        let pc = self.cpu.special_registers[0].value;
+        self.push((pc >> 0x8) as u8);
+        self.push((pc & 0xFF) as u8);
+        self.push(self.cpu.special_registers[2].value as u8);
+        self.push(status as u8);
+
+        self.cpu.special_registers[3].value &= !(1 << S_INTERRUPT_ENABLE);
+
+        self.cpu.special_registers[0].value = self.read_vector(vector);
+    }
+
+    // Like an interrupt, with the cause pushed on top of the frame. The
+    // handler pops it before returning with RETI.
+    fn enter_trap(&mut self, cause: u8) {
+        self.enter_interrupt(TRAP_VECTOR);
+        self.push(cause);
+    }
+
+    fn read_vector(&mut self, vector: u16) -> u16 {
+        (self.bus.read(vector) as u16) | ((self.bus.read(vector.wrapping_add(1)) as u16) << 0x8)
+    }
+
+    fn peek_vector(&self, vector: u16) -> u16 {
+        (self.bus.peek(vector) as u16) | ((self.bus.peek(vector.wrapping_add(1)) as u16) << 0x8)
+    }
+
+    fn jump_relative(&mut self, address: i16) {
+        self.cpu.special_registers[0].value = self.cpu.special_registers[0].value.wrapping_add_signed(address);
+    }
+
     fn get_opcode(&self, instruction: &u8) -> u8 {
         (instruction & 0xF0) >> 4
     }
 
     fn increment_pc(&mut self) {
-        self.cpu.special_registers[0].value += 1;
+        self.cpu.special_registers[0].value = self.cpu.special_registers[0].value.wrapping_add(1);
     }
 
     fn increment_sp(&mut self) {
-        self.cpu.special_registers[1].value += 1;
+        self.cpu.special_registers[1].value = self.cpu.special_registers[1].value.wrapping_add(1);
     }
 
     fn decrement_sp(&mut self) {
-        self.cpu.special_registers[1].value -= 1;
+        self.cpu.special_registers[1].value = self.cpu.special_registers[1].value.wrapping_sub(1);
     }
 
     fn halted(&self) -> bool {
-        return (self.cpu.special_registers[3].value & (1 << S_HALT)) != 0;
+        (self.cpu.special_registers[3].value & (1 << S_HALT)) != 0
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        (self.cpu.special_registers[3].value & (1 << S_INTERRUPT_ENABLE)) != 0
+    }
+
+    fn supervisor(&self) -> bool {
+        (self.cpu.special_registers[3].value & (1 << S_SUPERVISOR)) != 0
     }
 
     pub fn load(&mut self, start_addr: u16, data: Vec<u8>) {
-        for i in 0..data.len() {
-            self.memory.write(start_addr + i as u16, data[i]);
+        for (i, byte) in data.into_iter().enumerate() {
+            self.bus.burn(start_addr + i as u16, byte);
         }
     }
 
     pub fn dump(&self) {
         println!("PC: {:#06X} SP: {:#06X}", self.cpu.special_registers[0].value, self.cpu.special_registers[1].value);
+        println!("CYCLES: {}", self.cycles);
 
         println!();
 
-        for i in 0..6 {
-            println!("{}: {:#04X}", GENERAL_REGISTER_NAMES[i], self.cpu.general_registers[i].value);
+        for (i, name) in GENERAL_REGISTER_NAMES.iter().enumerate() {
+            println!("{}: {:#04X}", name, self.cpu.general_registers[i].value);
         }
 
         println!();
 
-        for i in 0..2 {
+        for (i, name) in FLAG_NAMES.iter().enumerate() {
             let flag_set = self.cpu.special_registers[2].value & (1 << i) != 0;
-            println!("{}: {}", FLAG_NAMES[i], if flag_set { "true" } else { "false" });
+            println!("{}: {}", name, if flag_set { "true" } else { "false" });
         }
 
         println!();
 
-        for i in 0..1 {
+        for (i, name) in STATUS_NAMES.iter().enumerate() {
             let status_set = self.cpu.special_registers[3].value & (1 << i) != 0;
-            println!("{}: {}", STATUS_NAMES[i], if status_set { "true" } else { "false" });
+            println!("{}: {}", name, if status_set { "true" } else { "false" });
         }
     }
 
-    pub fn mem_dump(&self, start_addr: u16, bytes: usize) {
+    pub fn micro_dump(&self) {
+        println!("IR: {:#04X} OR: {:#04X} TMP: {:#04X} MAR: {:#06X}", self.micro.ir, self.micro.or, self.micro.tmp, self.micro.mar);
+        println!("STEP: {} PAGE: {}", self.micro.step, if self.micro.page { "EXTENDED" } else { "BASE" });
+    }
+
+    pub fn interrupt_status(&self) {
+        println!("INTERRUPT_ENABLE: {}", if self.interrupts_enabled() { "true" } else { "false" });
+        println!("IRQ_PENDING: {}", if self.irq_pending || self.bus.irq() { "true" } else { "false" });
+        println!("NMI_PENDING: {}", if self.nmi_pending { "true" } else { "false" });
+
+        println!();
+
+        println!("TRAP_VECTOR: {:#06X} -> {:#06X}", TRAP_VECTOR, self.peek_vector(TRAP_VECTOR));
+        println!("STACK_VECTOR: {:#06X} -> {:#06X}", STACK_VECTOR, self.peek_vector(STACK_VECTOR));
+        println!("NMI_VECTOR: {:#06X} -> {:#06X}", NMI_VECTOR, self.peek_vector(NMI_VECTOR));
+        println!("RESET_VECTOR: {:#06X} -> {:#06X}", RESET_VECTOR, self.peek_vector(RESET_VECTOR));
+        println!("IRQ_VECTOR: {:#06X} -> {:#06X}", IRQ_VECTOR, self.peek_vector(IRQ_VECTOR));
+    }
+
+    pub fn mem_dump(&self, start_addr: u16, bytes: usize, bank: Option<u8>) {
         for i in 0..bytes {
-            print!("{:#04X} ", self.memory.read(start_addr + i as u16));
+            let address = start_addr + i as u16;
+
+            match bank {
+                Some(bank) => print!("{:#04X} ", self.bus.peek_bank(address, bank)),
+                None => print!("{:#04X} ", self.bus.peek(address))
+            }
         }
 
         println!();
     }
-}
\ No newline at end of file
+}
+
+impl Default for Computer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::WritePolicy;
+    use crate::devices::timer::Timer;
+
+    // A supervisor-built frame at FFF0 that returns to user mode at 0x0100,
+    // with the stack page closed to user mode.
+    fn return_to_user_mode(microcode: bool) -> Computer {
+        let mut computer = Computer::new();
+        computer.microcode = microcode;
+
+        computer.load(0x0000, vec![0x08, X_RETI << 4]);
+        computer.load(0xFFF0, vec![0x00, 0x01, 0x00, 0x01]);
+        computer.cpu.special_registers[1].value = 0xFFF0;
+        computer.bus.permissions[0xFF] = 0;
+
+        computer.step();
+        computer
+    }
+
+    #[test]
+    fn reti_enters_user_mode_with_a_protected_stack() {
+        for microcode in [false, true] {
+            let computer = return_to_user_mode(microcode);
+
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0100);
+            assert_eq!(computer.cpu.special_registers[1].value, 0xFFF4);
+            assert_eq!(computer.cpu.special_registers[2].value, 0x01);
+            assert_eq!(computer.cpu.special_registers[3].value & (1 << S_SUPERVISOR), 0);
+        }
+    }
+
+    // A machine in supervisor mode with the stack at 0x8000.
+    fn machine(microcode: bool) -> Computer {
+        let mut computer = Computer::new();
+        computer.microcode = microcode;
+        computer.cpu.special_registers[1].value = 0x8000;
+        computer
+    }
+
+    // The bytes from SP up to 0x8000, top of the stack first.
+    fn stack(computer: &Computer) -> Vec<u8> {
+        (computer.cpu.special_registers[1].value..0x8000).map(|address| computer.bus.peek(address)).collect()
+    }
+
+    #[test]
+    fn irq_entry_saves_a_frame_that_reti_restores() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(IRQ_VECTOR, vec![0x00, 0x02]);
+            computer.load(0x0200, vec![0x08, X_RETI << 4]);
+            computer.cpu.special_registers[0].value = 0x1234;
+            computer.cpu.special_registers[2].value = 1 << F_ZERO;
+            computer.cpu.special_registers[3].value = (1 << S_SUPERVISOR) | (1 << S_INTERRUPT_ENABLE);
+
+            computer.raise_irq();
+            assert_eq!(computer.step(), INTERRUPT_CYCLES);
+
+            // S, F, PC low, PC high.
+            assert_eq!(stack(&computer), vec![0x06, 0x01, 0x34, 0x12]);
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0200);
+            assert_eq!(computer.cpu.special_registers[3].value, 1 << S_SUPERVISOR);
+
+            computer.cpu.special_registers[2].value = 0;
+            computer.step();
+
+            assert_eq!(computer.cpu.special_registers[0].value, 0x1234);
+            assert_eq!(computer.cpu.special_registers[1].value, 0x8000);
+            assert_eq!(computer.cpu.special_registers[2].value, 1 << F_ZERO);
+            assert_eq!(computer.cpu.special_registers[3].value, (1 << S_SUPERVISOR) | (1 << S_INTERRUPT_ENABLE));
+        }
+    }
+
+    #[test]
+    fn irq_waits_for_ei_and_is_masked_by_di() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(IRQ_VECTOR, vec![0x00, 0x02]);
+
+            // NOP; EI; NOP at 0x0000 and DI at 0x0010.
+            computer.load(0x0000, vec![0x00, 0x08, X_EI << 4, 0x00]);
+            computer.load(0x0010, vec![0x08, X_DI << 4, 0x00]);
+
+            computer.raise_irq();
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0001);
+
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0003);
+            assert!(computer.interrupts_enabled());
+
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0200);
+            assert_eq!(stack(&computer)[2..], [0x03, 0x00]);
+
+            computer.cpu.special_registers[0].value = 0x0010;
+            computer.cpu.special_registers[3].value |= 1 << S_INTERRUPT_ENABLE;
+            computer.step();
+            assert!(!computer.interrupts_enabled());
+
+            computer.raise_irq();
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0013);
+        }
+    }
+
+    #[test]
+    fn interrupts_from_user_mode_run_in_supervisor_mode() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(IRQ_VECTOR, vec![0x00, 0x02]);
+            computer.load(0x0200, vec![0x08, X_RETI << 4]);
+            computer.cpu.special_registers[3].value = 1 << S_INTERRUPT_ENABLE;
+
+            computer.raise_irq();
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[3].value, 1 << S_SUPERVISOR);
+            assert_eq!(stack(&computer)[0], 1 << S_INTERRUPT_ENABLE);
+
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[3].value, 1 << S_INTERRUPT_ENABLE);
+        }
+    }
+
+    #[test]
+    fn reset_loads_pc_and_sp_from_the_vectors() {
+        let mut computer = Computer::new();
+        computer.load(STACK_VECTOR, vec![0x00, 0x90]);
+        computer.load(RESET_VECTOR, vec![0x34, 0x12]);
+        computer.load(0x1000, vec![0xAB]);
+        computer.cpu.general_registers[0].value = 0x55;
+        computer.cpu.special_registers[2].value = 0x03;
+        computer.cpu.special_registers[3].value = (1 << S_HALT) | (1 << S_INTERRUPT_ENABLE);
+        computer.raise_irq();
+
+        computer.reset();
+
+        assert_eq!(registers(&computer), vec![0, 0, 0, 0, 0, 0, 0x1234, 0x9000, 0, 1 << S_SUPERVISOR]);
+        assert!(!computer.irq_pending);
+        assert_eq!(computer.bus.peek(0x1000), 0xAB);
+    }
+
+    #[test]
+    fn blank_stack_vector_puts_the_stack_below_the_vectors() {
+        let mut computer = Computer::new();
+        computer.load(RESET_VECTOR, vec![0x00, 0x01]);
+
+        computer.reset();
+
+        assert_eq!(computer.cpu.special_registers[0].value, 0x0100);
+        assert_eq!(computer.cpu.special_registers[1].value, TRAP_VECTOR);
+    }
+
+    #[test]
+    fn power_on_clears_memory_and_cycles() {
+        let mut computer = Computer::new();
+        computer.load(0x1000, vec![0xAB]);
+        computer.load(RESET_VECTOR, vec![0x34, 0x12]);
+        computer.bus.permissions[0x10] = 0;
+        computer.step();
+
+        computer.power_on();
+
+        assert_eq!(computer.bus.peek(0x1000), 0);
+        assert_eq!(computer.bus.permissions[0x10], ALL);
+        assert_eq!(computer.cycles, 0);
+        assert_eq!(computer.cpu.special_registers[0].value, 0x0000);
+    }
+
+    #[test]
+    fn nmi_is_taken_while_interrupts_are_masked() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(NMI_VECTOR, vec![0x00, 0x03]);
+            computer.load(IRQ_VECTOR, vec![0x00, 0x02]);
+            computer.cpu.special_registers[3].value = 1 << S_SUPERVISOR;
+
+            computer.raise_irq();
+            computer.raise_nmi();
+            assert_eq!(computer.step(), INTERRUPT_CYCLES);
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0300);
+
+            // The IRQ stays pending behind the NMI until it is unmasked.
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0301);
+            assert!(computer.irq_pending);
+        }
+    }
+
+    #[test]
+    fn nmi_wakes_a_halted_cpu() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(NMI_VECTOR, vec![0x00, 0x03]);
+            computer.load(0x0000, vec![0xF0]);
+
+            computer.step();
+            assert!(computer.halted());
+            assert_eq!(computer.step(), IDLE_CYCLES);
+
+            computer.raise_nmi();
+            computer.step();
+            assert!(!computer.halted());
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0300);
+            assert_eq!(stack(&computer)[2..], [0x01, 0x00]);
+        }
+    }
+
+    #[test]
+    fn writing_faulting_rom_halts_the_cpu() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.bus.map_rom("ROM", 0xF000, 0xF0FF, WritePolicy::Fault).unwrap();
+
+            // STR A, [0xF000] with A 0x42, loaded through the burner.
+            computer.load(0xF000, vec![0x99]);
+            computer.load(0x0000, vec![0x30, 0x00, 0xF0]);
+            computer.cpu.general_registers[0].value = 0x42;
+
+            computer.step();
+
+            assert!(computer.halted());
+            assert_eq!(computer.fault.map(|fault| (fault.addr, fault.value)), Some((0xF000, 0x42)));
+            assert_eq!(computer.bus.peek(0xF000), 0x99);
+        }
+    }
+
+    const ALL: u8 = (1 << PAGE_READ) | (1 << PAGE_WRITE) | (1 << PAGE_EXECUTE);
+
+    // Runs one user mode step of `program` at 0x0000 with page 0x90 limited
+    // to `permissions`, HL pointing into it and the trap handler at 0x0300.
+    fn user_step(microcode: bool, program: &[u8], permissions: u8) -> Computer {
+        let mut computer = machine(microcode);
+        computer.load(TRAP_VECTOR, vec![0x00, 0x03]);
+        computer.load(0x0000, program.to_vec());
+        computer.load(0x9000, vec![0x77]);
+        computer.cpu.set_hl(0x9000);
+        computer.cpu.special_registers[3].value = 0;
+        computer.bus.permissions[0x90] = permissions;
+
+        assert_eq!(computer.step(), TRAP_CYCLES);
+        assert_eq!(computer.cpu.special_registers[0].value, 0x0300);
+        assert_eq!(computer.cpu.special_registers[3].value, 1 << S_SUPERVISOR);
+
+        computer
+    }
+
+    #[test]
+    fn traps_push_the_cause_on_the_interrupt_frame() {
+        for microcode in [false, true] {
+            // Cause, S, F, PC low, PC high.
+            let cases: [(&[u8], u8, [u8; 5]); 5] = [
+                (&[0x20, 0x00, 0x90], ALL & !(1 << PAGE_READ), [TRAP_READ, 0, 0, 0x00, 0x00]),
+                (&[0x30, 0x00, 0x90], ALL & !(1 << PAGE_WRITE), [TRAP_WRITE, 0, 0, 0x00, 0x00]),
+                (&[0xF0], ALL, [TRAP_PRIVILEGED, 0, 0, 0x00, 0x00]),
+                (&[0x08, X_RETI << 4], ALL, [TRAP_PRIVILEGED, 0, 0, 0x00, 0x00]),
+                (&[0x08, X_SYSCALL << 4], ALL, [TRAP_SYSCALL, 0, 0, 0x02, 0x00])
+            ];
+
+            for (program, permissions, frame) in cases {
+                let computer = user_step(microcode, program, permissions);
+
+                assert_eq!(stack(&computer), frame, "program {:02X?}", program);
+            }
+        }
+    }
+
+    #[test]
+    fn fetching_from_a_page_without_execute_traps() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(TRAP_VECTOR, vec![0x00, 0x03]);
+            computer.load(0x9000, vec![0x10, 0x55]);
+            computer.cpu.special_registers[0].value = 0x9000;
+            computer.cpu.special_registers[3].value = 0;
+            computer.bus.permissions[0x90] = ALL & !(1 << PAGE_EXECUTE);
+
+            assert_eq!(computer.step(), TRAP_CYCLES);
+            assert_eq!(stack(&computer), vec![TRAP_EXECUTE, 0, 0, 0x00, 0x90]);
+            assert_eq!(computer.cpu.general_registers[0].value, 0);
+        }
+    }
+
+    #[test]
+    fn a_trapping_instruction_is_undone() {
+        for microcode in [false, true] {
+            // LDR A, [HL+] and STR A, [HL-] leave HL and memory alone.
+            let computer = user_step(microcode, &[0x08, X_LDR_INC << 4], ALL & !(1 << PAGE_READ));
+            assert_eq!(computer.cpu.hl(), 0x9000);
+            assert_eq!(computer.cpu.general_registers[0].value, 0);
+
+            let computer = user_step(microcode, &[0x08, X_STR_DEC << 4], ALL & !(1 << PAGE_WRITE));
+            assert_eq!(computer.cpu.hl(), 0x9000);
+            assert_eq!(computer.bus.peek(0x9000), 0x77);
+        }
+    }
+
+    #[test]
+    fn the_stack_is_checked_for_push_and_pop() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(TRAP_VECTOR, vec![0x00, 0x03]);
+            computer.load(0x0000, vec![0x50, 0x42]);
+            computer.cpu.special_registers[3].value = 0;
+            computer.bus.permissions[0x7F] = ALL & !(1 << PAGE_WRITE);
+
+            computer.step();
+            assert_eq!(stack(&computer), vec![TRAP_WRITE, 0, 0, 0x00, 0x00]);
+            assert_eq!(computer.bus.peek(0x7FFF), 0x00);
+
+            let mut computer = machine(microcode);
+            computer.load(TRAP_VECTOR, vec![0x00, 0x03]);
+            computer.load(0x0000, vec![0x60]);
+            computer.cpu.special_registers[1].value = 0x7FFF;
+            computer.cpu.special_registers[3].value = 0;
+            computer.bus.permissions[0x7F] = ALL & !(1 << PAGE_READ);
+
+            computer.step();
+            assert_eq!(computer.bus.peek(computer.cpu.special_registers[1].value), TRAP_READ);
+            assert_eq!(computer.cpu.special_registers[1].value, 0x7FFF - 5);
+        }
+    }
+
+    #[test]
+    fn supervisor_mode_ignores_page_permissions() {
+        for microcode in [false, true] {
+            let mut computer = machine(microcode);
+            computer.load(0x0000, vec![0x20, 0x00, 0x90, 0x30, 0x01, 0x90]);
+            computer.load(0x9000, vec![0x77]);
+            computer.bus.permissions[0x90] = 0;
+
+            computer.step();
+            computer.step();
+
+            assert_eq!(computer.cpu.general_registers[0].value, 0x77);
+            assert_eq!(computer.bus.peek(0x9001), 0x77);
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0006);
+        }
+    }
+
+    fn registers(computer: &Computer) -> Vec<u16> {
+        let general = computer.cpu.general_registers.iter().map(|register| register.value as u16);
+        let special = computer.cpu.special_registers.iter().map(|register| register.value);
+
+        general.chain(special).collect()
+    }
+
+    // Register values and the two bytes after the instruction. The later
+    // ones carry, borrow, jump back past 0x0000 and name register 7.
+    const STATES: [([u8; 6], [u8; 2]); 3] = [
+        ([0x40; 6], [0x01, 0x00]),
+        ([0x01, 0x80, 0xFF, 0x00, 0x10, 0x20], [0xF2, 0xFF]),
+        ([0x00, 0x01, 0x7F, 0xFE, 0xF0, 0xFF], [0x07, 0x80])
+    ];
+
+    // Runs one step of `program` in both modes from the same state and checks
+    // they agree on the cycles taken and the result.
+    fn assert_modes_agree(program: &[u8], flags: u16, setup: fn(&mut Computer)) {
+        for (values, operands) in STATES {
+            let mut results = Vec::new();
+
+            for microcode in [false, true] {
+                let mut computer = Computer::new();
+                computer.microcode = microcode;
+
+                for (register, value) in computer.cpu.general_registers.iter_mut().zip(values) {
+                    register.value = value;
+                }
+
+                computer.cpu.special_registers[1].value = 0x8000;
+                computer.cpu.special_registers[2].value = flags;
+                computer.load(0x0000, [program, &operands].concat());
+                setup(&mut computer);
+
+                let cycles = computer.step();
+                results.push((cycles, registers(&computer)));
+            }
+
+            assert_eq!(results[0], results[1], "program {:02X?}, flags {:#04X}, registers {:02X?}", program, flags, values);
+        }
+    }
+
+    #[test]
+    fn instruction_cycles_match_microcode() {
+        // Every instruction byte, on its own and after each extended prefix.
+        for flags in 0..4 {
+            for instruction in 0..=0xFFu8 {
+                if instruction & 0xF8 != 0x08 {
+                    assert_modes_agree(&[instruction], flags, |_| {});
+                }
+
+                for prefix in 0x08..=0x0F {
+                    assert_modes_agree(&[prefix, instruction], flags, |_| {});
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn acknowledged_device_irq_is_taken_once() {
+        for microcode in [false, true] {
+            let mut computer = Computer::new();
+            computer.microcode = microcode;
+            computer.bus.map_device("TIMER", 0xF000, 0xF006, Box::new(Timer::new())).unwrap();
+
+            // Counts 2 with IRQ on, enables interrupts and loops. The handler
+            // at 0x0100 counts in B, acknowledges the timer and returns.
+            computer.load(0x0000, vec![
+                0x10, 0x02, 0x30, 0x00, 0xF0,
+                0x10, 0x05, 0x30, 0x05, 0xF0,
+                0x08, X_EI << 4,
+                0x70, 0xFD, 0xFF
+            ]);
+            computer.load(0x0100, vec![0x91, 0x01, 0x30, 0x06, 0xF0, 0x08, X_RETI << 4]);
+            computer.load(IRQ_VECTOR, vec![0x00, 0x01]);
+
+            for _ in 0..40 {
+                computer.step();
+            }
+
+            assert_eq!(computer.cpu.general_registers[1].value, 1);
+        }
+    }
+
+    #[test]
+    fn jz_skips_its_operand_when_not_taken() {
+        for microcode in [false, true] {
+            // (flags, program, PC after, cycles)
+            let cases: [(u16, &[u8], u16, u64); 4] = [
+                (1 << F_ZERO, &[0x80, 0x10, 0x00], 0x0003, 3),
+                (0, &[0x80, 0x10, 0x00], 0x0013, 4),
+                (1 << F_ZERO, &[0x88], 0x0001, 2),
+                (0, &[0x88], 0x0021, 3)
+            ];
+
+            for (flags, program, pc, cycles) in cases {
+                let mut computer = machine(microcode);
+                computer.load(0x0000, program.to_vec());
+                computer.cpu.set_hl(0x0020);
+                computer.cpu.special_registers[2].value = flags;
+
+                assert_eq!(computer.step(), cycles);
+                assert_eq!(computer.cpu.special_registers[0].value, pc, "program {:02X?}, flags {:#04X}", program, flags);
+            }
+        }
+    }
+
+    #[test]
+    fn subtraction_and_jumps_wrap() {
+        for microcode in [false, true] {
+            let mut computer = Computer::new();
+            computer.microcode = microcode;
+
+            // MOV A, 0; SUB A, 1; JMP -7
+            computer.load(0x0000, vec![0x10, 0x00, 0xC0, 0x01, 0x70, 0xF9, 0xFF]);
+            computer.step();
+            computer.step();
+            assert_eq!(computer.cpu.general_registers[0].value, 0xFF);
+
+            computer.step();
+            assert_eq!(computer.cpu.special_registers[0].value, 0x0000);
+        }
+    }
+
+    #[test]
+    fn missing_registers_read_as_zero() {
+        for microcode in [false, true] {
+            let mut computer = Computer::new();
+            computer.microcode = microcode;
+
+            // MOV 7, 5; MOV A, 7; 0F 20 (LDR A, [HL+]) and HLT.
+            computer.cpu.general_registers[0].value = 0x55;
+            computer.load(0x0000, vec![0x17, 0x05, 0x18, 0x07, 0x0F, 0x20, 0xF0]);
+            computer.step();
+            computer.step();
+            assert_eq!(computer.cpu.general_registers[0].value, 0x00);
+
+            computer.run(None, &AtomicBool::new(false));
+            assert_eq!(computer.cpu.general_registers[0].value, 0x17);
+            assert_eq!(computer.cpu.hl(), 0x0001);
+        }
+    }
+
+    #[test]
+    fn interrupt_and_trap_cycles_match_microcode() {
+        assert_modes_agree(&[0x00], 0, |computer| computer.raise_irq());
+        assert_modes_agree(&[0x00], 0, |computer| computer.raise_nmi());
+
+        // Privileged instructions and protected pages in user mode.
+        assert_modes_agree(&[0xF0], 0, |computer| computer.cpu.special_registers[3].value = 0);
+        assert_modes_agree(&[0x08, X_EI << 4], 0, |computer| computer.cpu.special_registers[3].value = 0);
+        assert_modes_agree(&[0x20, 0x00, 0x90], 0, |computer| {
+            computer.cpu.special_registers[3].value = 0;
+            computer.bus.permissions[0x90] = 0;
+        });
+        assert_modes_agree(&[0x08, X_STR_OFFSET << 4, 0x10], 0, |computer| {
+            computer.cpu.special_registers[3].value = 0;
+            computer.bus.permissions[0x40] = 0;
+        });
+        assert_modes_agree(&[0x00], 0, |computer| {
+            computer.cpu.special_registers[3].value = 0;
+            computer.bus.permissions[0x00] = 0;
+        });
+    }
+}
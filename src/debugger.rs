@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+
+use crate::bus::{Bus, SystemBus};
+use crate::computer::{CPU, FLAG_NAMES, GENERAL_REGISTER_NAMES, SPECIAL_REGISTER_NAMES};
+
+pub struct Breakpoint {
+    pub id: usize,
+    pub address: u16,
+    pub condition: Option<(String, Expr)>,
+    pub enabled: bool,
+    pub hits: u64,
+    // Hits still to pass over before stopping.
+    pub ignore: u64
+}
+
+// Watches an inclusive address range for CPU reads, writes or both.
+pub struct Watchpoint {
+    pub id: usize,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub enabled: bool,
+    pub hits: u64
+}
+
+// The first watched access of an instruction. For a read, old and new are
+// both the value read.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct WatchHit {
+    pub id: usize,
+    pub address: u16,
+    pub pc: u16,
+    pub old: u8,
+    pub new: u8,
+    pub write: bool
+}
+
+// Breakpoints, watchpoints and symbols. Breakpoints and watchpoints share
+// one numbering. Symbols name addresses for BREAK and WATCH and for labels
+// in conditions, which are resolved when the breakpoint is set.
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub symbols: HashMap<String, u16>,
+    next_id: usize
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            symbols: HashMap::new(),
+            next_id: 1
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, target: &str, condition: Option<&str>) -> Result<usize, String> {
+        let address = self.resolve(target)?;
+
+        let condition = match condition {
+            Some(text) => Some((text.to_string(), parse_expression(text, &self.symbols)?)),
+            None => None
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.breakpoints.push(Breakpoint {
+            id,
+            address,
+            condition,
+            enabled: true,
+            hits: 0,
+            ignore: 0
+        });
+
+        Ok(id)
+    }
+
+    // `range` is "<addr>" or "<start>..<end>", each an address or symbol,
+    // and `access` one of r, w or rw (w by default).
+    pub fn add_watchpoint(&mut self, range: &str, access: Option<&str>) -> Result<usize, String> {
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (self.resolve(start)?, self.resolve(end)?),
+            None => (self.resolve(range)?, self.resolve(range)?)
+        };
+
+        if end < start {
+            return Err(String::from("Watch range ends before it starts"));
+        }
+
+        let (read, write) = match access {
+            Some("r") => (true, false),
+            Some("w") | None => (false, true),
+            Some("rw") => (true, true),
+            Some(access) => return Err(format!("Invalid access: {}", access))
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.watchpoints.push(Watchpoint {
+            id,
+            start,
+            end,
+            read,
+            write,
+            enabled: true,
+            hits: 0
+        });
+
+        Ok(id)
+    }
+
+    pub fn delete(&mut self, id: usize) -> Result<(), String> {
+        if let Some(index) = self.watchpoints.iter().position(|watchpoint| watchpoint.id == id) {
+            self.watchpoints.remove(index);
+            return Ok(());
+        }
+
+        let index = self.index(id)?;
+        self.breakpoints.remove(index);
+
+        Ok(())
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> Result<(), String> {
+        if let Some(watchpoint) = self.watchpoints.iter_mut().find(|watchpoint| watchpoint.id == id) {
+            watchpoint.enabled = enabled;
+            return Ok(());
+        }
+
+        let index = self.index(id)?;
+        self.breakpoints[index].enabled = enabled;
+
+        Ok(())
+    }
+
+    pub fn set_ignore(&mut self, id: usize, count: u64) -> Result<(), String> {
+        let index = self.index(id)?;
+        self.breakpoints[index].ignore = count;
+
+        Ok(())
+    }
+
+    // Counts a hit on every enabled breakpoint at `address` whose condition
+    // holds, and returns the first one that is not being ignored.
+    pub fn check(&mut self, address: u16, cpu: &CPU, bus: &SystemBus) -> Option<usize> {
+        let mut stop = None;
+
+        for breakpoint in self.breakpoints.iter_mut().filter(|breakpoint| breakpoint.enabled && breakpoint.address == address) {
+            if breakpoint.condition.as_ref().is_some_and(|(_, condition)| condition.evaluate(cpu, bus) == 0) {
+                continue;
+            }
+
+            breakpoint.hits += 1;
+
+            if breakpoint.ignore > 0 {
+                breakpoint.ignore -= 1;
+            } else if stop.is_none() {
+                stop = Some(breakpoint.id);
+            }
+        }
+
+        stop
+    }
+
+    pub fn watching(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    // Counts a hit on every enabled watchpoint covering the access and
+    // returns the first.
+    pub fn check_access(&mut self, address: u16, write: bool) -> Option<usize> {
+        let mut hit = None;
+
+        for watchpoint in &mut self.watchpoints {
+            let watched = if write { watchpoint.write } else { watchpoint.read };
+
+            if watchpoint.enabled && watched && watchpoint.start <= address && address <= watchpoint.end {
+                watchpoint.hits += 1;
+                hit = hit.or(Some(watchpoint.id));
+            }
+        }
+
+        hit
+    }
+
+    // Each line is "<name> <address>", with the address in the same 0x, 0b
+    // or decimal notation LOAD uses. Text after ';' is ignored.
+    pub fn load_symbols(&mut self, path: &str) -> std::io::Result<usize> {
+        let mut count = 0;
+
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line[0..line.find(';').unwrap_or(line.len())].trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            let address = match fields[..] {
+                [_, address] => parse_number(address).filter(|&address| address <= 0xFFFF),
+                _ => None
+            };
+
+            match address {
+                Some(address) => self.symbols.insert(fields[0].to_string(), address as u16),
+                None => return Err(std::io::Error::other(format!("Invalid symbol: {}", line)))
+            };
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // A symbol name, or a hex address as elsewhere in the REPL.
+    pub fn resolve(&self, target: &str) -> Result<u16, String> {
+        match self.symbols.get(target) {
+            Some(&address) => Ok(address),
+            None => u16::from_str_radix(target, 16).map_err(|_| format!("Unknown address or symbol: {}", target))
+        }
+    }
+
+    pub fn symbol_at(&self, address: u16) -> Option<&str> {
+        self.symbols.iter().find(|(_, &value)| value == address).map(|(name, _)| name.as_str())
+    }
+
+    fn index(&self, id: usize) -> Result<usize, String> {
+        self.breakpoints.iter().position(|breakpoint| breakpoint.id == id).ok_or(format!("No breakpoint or watchpoint {}", id))
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Operator {
+    Or, And,
+    Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual,
+    Add, Subtract, BitAnd, BitOr
+}
+
+// Condition expressions over registers (A-H, PC, SP, F, S, HL), flags (ZERO,
+// CARRY), memory ([address]), numbers and symbols. Comparisons and logic
+// evaluate to 1 or 0, and anything non-zero is true.
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(i64),
+    General(usize),
+    Special(usize),
+    Flag(usize),
+    HL,
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>)
+}
+
+impl Expr {
+    pub fn evaluate(&self, cpu: &CPU, bus: &SystemBus) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::General(index) => cpu.general_registers[*index].value as i64,
+            Expr::Special(index) => cpu.special_registers[*index].value as i64,
+            Expr::Flag(flag) => ((cpu.special_registers[2].value >> flag) & 1) as i64,
+            Expr::HL => cpu.hl() as i64,
+            Expr::Memory(address) => bus.peek(address.evaluate(cpu, bus) as u16) as i64,
+            Expr::Not(operand) => (operand.evaluate(cpu, bus) == 0) as i64,
+            Expr::Binary(operator, left, right) => {
+                let left = left.evaluate(cpu, bus);
+
+                // Short-circuit so a false guard can skip a memory read.
+                match operator {
+                    Operator::And if left == 0 => return 0,
+                    Operator::Or if left != 0 => return 1,
+                    _ => {}
+                }
+
+                let right = right.evaluate(cpu, bus);
+
+                match operator {
+                    Operator::Or | Operator::And => (right != 0) as i64,
+                    Operator::Equal => (left == right) as i64,
+                    Operator::NotEqual => (left != right) as i64,
+                    Operator::Less => (left < right) as i64,
+                    Operator::LessEqual => (left <= right) as i64,
+                    Operator::Greater => (left > right) as i64,
+                    Operator::GreaterEqual => (left >= right) as i64,
+                    Operator::Add => left.wrapping_add(right),
+                    Operator::Subtract => left.wrapping_sub(right),
+                    Operator::BitAnd => left & right,
+                    Operator::BitOr => left | right
+                }
+            }
+        }
+    }
+}
+
+// Numbers in 0x (hex), 0b (binary) or decimal notation.
+pub fn parse_number(text: &str) -> Option<i64> {
+    match text.get(0..2) {
+        Some("0x" | "0X") => i64::from_str_radix(&text[2..], 16).ok(),
+        Some("0b") => i64::from_str_radix(&text[2..], 2).ok(),
+        _ => text.parse::<i64>().ok()
+    }
+}
+
+pub fn parse_expression(text: &str, symbols: &HashMap<String, u16>) -> Result<Expr, String> {
+    let tokens = tokenize(text)?;
+    let mut parser = Parser { tokens, position: 0, symbols };
+
+    let expression = parser.parse_binary(0)?;
+
+    match parser.tokens.get(parser.position) {
+        Some(token) => Err(format!("Unexpected '{}'", token)),
+        None => Ok(expression)
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    // Two-character operators first so they are not split.
+    const SYMBOLS: [&str; 17] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "|", "!", "[", "]", "(", ")"];
+
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            tokens.push(symbol.to_string());
+            rest = &rest[symbol.len()..];
+        } else {
+            let length = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+
+            if length == 0 {
+                return Err(format!("Unexpected '{}'", &rest[0..1]));
+            }
+
+            tokens.push(rest[0..length].to_string());
+            rest = &rest[length..];
+        }
+
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+// Lowest precedence first.
+const PRECEDENCE: [&[(&str, Operator)]; 4] = [
+    &[("||", Operator::Or)],
+    &[("&&", Operator::And)],
+    &[
+        ("==", Operator::Equal), ("!=", Operator::NotEqual),
+        ("<", Operator::Less), ("<=", Operator::LessEqual),
+        (">", Operator::Greater), (">=", Operator::GreaterEqual)
+    ],
+    &[("+", Operator::Add), ("-", Operator::Subtract), ("&", Operator::BitAnd), ("|", Operator::BitOr)]
+];
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    position: usize,
+    symbols: &'a HashMap<String, u16>
+}
+
+impl Parser<'_> {
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+
+        let mut left = self.parse_binary(level + 1)?;
+
+        while let Some(&(_, operator)) = self.peek().and_then(|token| PRECEDENCE[level].iter().find(|(symbol, _)| *symbol == token)) {
+            self.position += 1;
+
+            let right = self.parse_binary(level + 1)?;
+            left = Expr::Binary(operator, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        let token = match self.tokens.get(self.position) {
+            Some(token) => token.clone(),
+            None => return Err(String::from("Unexpected end of expression"))
+        };
+
+        self.position += 1;
+
+        match token.as_str() {
+            "!" => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            "[" => {
+                let address = self.parse_binary(0)?;
+                self.expect("]")?;
+
+                Ok(Expr::Memory(Box::new(address)))
+            },
+            "(" => {
+                let expression = self.parse_binary(0)?;
+                self.expect(")")?;
+
+                Ok(expression)
+            },
+            "HL" => Ok(Expr::HL),
+            name => {
+                if let Some(index) = GENERAL_REGISTER_NAMES.iter().position(|register| *register == name) {
+                    Ok(Expr::General(index))
+                } else if let Some(index) = SPECIAL_REGISTER_NAMES.iter().position(|register| *register == name) {
+                    Ok(Expr::Special(index))
+                } else if let Some(flag) = FLAG_NAMES.iter().position(|flag| *flag == name) {
+                    Ok(Expr::Flag(flag))
+                } else if let Some(&address) = self.symbols.get(name) {
+                    Ok(Expr::Number(address as i64))
+                } else {
+                    parse_number(name).map(Expr::Number).ok_or(format!("Unknown name '{}'", name))
+                }
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.peek() != Some(token) {
+            return Err(format!("Expected '{}'", token));
+        }
+
+        self.position += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(text: &str) -> i64 {
+        let mut symbols = HashMap::new();
+        symbols.insert(String::from("buffer"), 0x1234);
+
+        let mut cpu = CPU::new();
+        cpu.general_registers[0].value = 5;
+        cpu.general_registers[4].value = 0x00;
+        cpu.general_registers[5].value = 0x20;
+
+        let mut bus = SystemBus::new();
+        bus.write(0x2000, 42);
+
+        parse_expression(text, &symbols).unwrap().evaluate(&cpu, &bus)
+    }
+
+    fn error(text: &str) -> String {
+        parse_expression(text, &HashMap::new()).unwrap_err()
+    }
+
+    #[test]
+    fn numbers_accept_hex_binary_and_decimal() {
+        assert_eq!(parse_number("0x1F"), Some(0x1F));
+        assert_eq!(parse_number("0X1f"), Some(0x1F));
+        assert_eq!(parse_number("0b101"), Some(5));
+        assert_eq!(parse_number("17"), Some(17));
+        assert_eq!(parse_number("0xZZ"), None);
+        assert_eq!(parse_number("A"), None);
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        assert_eq!(evaluate("1 + 2 == 3"), 1);
+        assert_eq!(evaluate("1 == 1 && 0"), 0);
+        assert_eq!(evaluate("0 && 0 || 1"), 1);
+        assert_eq!(evaluate("1 || 0 && 0"), 1);
+        assert_eq!(evaluate("10 - 2 - 3"), 5);
+        assert_eq!(evaluate("(1 || 0) && 0"), 0);
+        assert_eq!(evaluate("!0 + 1"), 2);
+        assert_eq!(evaluate("!(0 + 1)"), 0);
+        assert_eq!(evaluate("6 & 3 | 8"), 10);
+    }
+
+    #[test]
+    fn names_read_registers_memory_and_symbols() {
+        assert_eq!(evaluate("A == 5"), 1);
+        assert_eq!(evaluate("HL"), 0x2000);
+        assert_eq!(evaluate("[HL] == 42"), 1);
+        assert_eq!(evaluate("[0x2000] + 1"), 43);
+        assert_eq!(evaluate("buffer"), 0x1234);
+        assert_eq!(evaluate("ZERO"), 0);
+    }
+
+    #[test]
+    fn logic_evaluates_to_one_or_zero() {
+        assert_eq!(evaluate("2 && 3"), 1);
+        assert_eq!(evaluate("0 || 5"), 1);
+        assert_eq!(evaluate("5 || 0"), 1);
+        assert_eq!(evaluate("0 && 5"), 0);
+        assert_eq!(evaluate("!7"), 0);
+    }
+
+    #[test]
+    fn bad_expressions_are_rejected() {
+        assert_eq!(error("1 +"), "Unexpected end of expression");
+        assert_eq!(error(""), "Unexpected end of expression");
+        assert_eq!(error("1 2"), "Unexpected '2'");
+        assert_eq!(error("A == $"), "Unexpected '$'");
+        assert_eq!(error("[HL == 1"), "Expected ']'");
+        assert_eq!(error("(1 + 2"), "Expected ')'");
+        assert_eq!(error("missing == 1"), "Unknown name 'missing'");
+    }
+
+    #[test]
+    fn breakpoints_count_hits_and_honour_ignore() {
+        let mut debugger = Debugger::new();
+        let cpu = CPU::new();
+        let bus = SystemBus::new();
+
+        let id = debugger.add_breakpoint("100", None).unwrap();
+        debugger.set_ignore(id, 2).unwrap();
+
+        assert_eq!(debugger.check(0x100, &cpu, &bus), None);
+        assert_eq!(debugger.check(0x100, &cpu, &bus), None);
+        assert_eq!(debugger.check(0x100, &cpu, &bus), Some(id));
+        assert_eq!(debugger.check(0x101, &cpu, &bus), None);
+        assert_eq!(debugger.breakpoints[0].hits, 3);
+        assert_eq!(debugger.breakpoints[0].ignore, 0);
+
+        debugger.set_enabled(id, false).unwrap();
+        assert_eq!(debugger.check(0x100, &cpu, &bus), None);
+        assert_eq!(debugger.breakpoints[0].hits, 3);
+    }
+
+    #[test]
+    fn false_conditions_are_not_counted() {
+        let mut debugger = Debugger::new();
+        let mut cpu = CPU::new();
+        let bus = SystemBus::new();
+
+        let id = debugger.add_breakpoint("100", Some("A == 1")).unwrap();
+
+        assert_eq!(debugger.check(0x100, &cpu, &bus), None);
+        assert_eq!(debugger.breakpoints[0].hits, 0);
+
+        cpu.general_registers[0].value = 1;
+        assert_eq!(debugger.check(0x100, &cpu, &bus), Some(id));
+        assert_eq!(debugger.breakpoints[0].hits, 1);
+    }
+
+    #[test]
+    fn single_address_watch_covers_only_that_address() {
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint("2000", None).unwrap();
+
+        assert_eq!((debugger.watchpoints[0].start, debugger.watchpoints[0].end), (0x2000, 0x2000));
+        assert_eq!(debugger.check_access(0x1FFF, true), None);
+        assert_eq!(debugger.check_access(0x2000, true), Some(id));
+        assert_eq!(debugger.check_access(0x2001, true), None);
+    }
+
+    #[test]
+    fn watch_range_is_inclusive_up_to_the_top_of_memory() {
+        let mut debugger = Debugger::new();
+        let id = debugger.add_watchpoint("FFF0..FFFF", None).unwrap();
+
+        assert_eq!(debugger.check_access(0xFFEF, true), None);
+        assert_eq!(debugger.check_access(0xFFF0, true), Some(id));
+        assert_eq!(debugger.check_access(0xFFFF, true), Some(id));
+        assert_eq!(debugger.check_access(0x0000, true), None);
+        assert_eq!(debugger.watchpoints[0].hits, 2);
+    }
+
+    #[test]
+    fn backwards_ranges_and_bad_access_are_rejected() {
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.add_watchpoint("2001..2000", None), Err(String::from("Watch range ends before it starts")));
+        assert_eq!(debugger.add_watchpoint("2000", Some("x")), Err(String::from("Invalid access: x")));
+        assert!(!debugger.watching());
+    }
+
+    #[test]
+    fn watches_filter_reads_and_writes() {
+        let mut debugger = Debugger::new();
+        let read = debugger.add_watchpoint("1000", Some("r")).unwrap();
+        let write = debugger.add_watchpoint("1000", Some("w")).unwrap();
+        let access = debugger.add_watchpoint("1000", Some("rw")).unwrap();
+
+        assert_eq!(debugger.check_access(0x1000, false), Some(read));
+        assert_eq!(debugger.check_access(0x1000, true), Some(write));
+
+        let hits: Vec<u64> = debugger.watchpoints.iter().map(|watchpoint| watchpoint.hits).collect();
+        assert_eq!(hits, vec![1, 1, 2]);
+
+        debugger.delete(read).unwrap();
+        debugger.delete(write).unwrap();
+        assert_eq!(debugger.check_access(0x1000, false), Some(access));
+
+        debugger.set_enabled(access, false).unwrap();
+        assert_eq!(debugger.check_access(0x1000, false), None);
+        assert_eq!(debugger.check_access(0x1000, true), None);
+    }
+}
@@ -0,0 +1,190 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::bus::Device;
+
+pub const DISK_SECTOR_LOW: u16 = 0x0;
+pub const DISK_SECTOR_HIGH: u16 = 0x1;
+pub const DISK_COMMAND: u16 = 0x2;
+pub const DISK_STATUS: u16 = 0x3;
+pub const DISK_DATA: u16 = 0x4;
+pub const DISK_INDEX: u16 = 0x5;
+pub const DISK_SIZE: u16 = 0x6;
+
+pub const SECTOR_SIZE: usize = 256;
+
+const COMMAND_READ: u8 = 0x01;
+const COMMAND_WRITE: u8 = 0x02;
+
+const STATUS_ERROR: u8 = 0x00;
+const STATUS_READ_ONLY: u8 = 0x01;
+
+// Block storage backed by a host disk image. Transfers go through a one
+// sector buffer: READ fills it from the image, WRITE stores it back, and DATA
+// moves one byte at INDEX, advancing it. Sectors past the end of the image
+// read as zeros and writing them grows the file.
+pub struct Disk {
+    file: File,
+    writable: bool,
+    pub sector: u16,
+    pub status: u8,
+    pub index: u8,
+    pub buffer: [u8; SECTOR_SIZE]
+}
+
+impl Disk {
+    pub fn open(path: &str, writable: bool) -> std::io::Result<Disk> {
+        let file = OpenOptions::new().read(true).write(writable).open(path)?;
+
+        Ok(Disk {
+            file,
+            writable,
+            sector: 0,
+            status: if writable { 0 } else { 1 << STATUS_READ_ONLY },
+            index: 0,
+            buffer: [0; SECTOR_SIZE]
+        })
+    }
+
+    fn execute(&mut self, command: u8) {
+        let result = match command {
+            COMMAND_READ => self.read_sector(),
+            COMMAND_WRITE if self.writable => self.write_sector(),
+            _ => Err(std::io::Error::other("invalid disk command"))
+        };
+
+        self.index = 0;
+
+        match result {
+            Ok(()) => self.status &= !(1 << STATUS_ERROR),
+            Err(_) => self.status |= 1 << STATUS_ERROR
+        }
+    }
+
+    fn read_sector(&mut self) -> std::io::Result<()> {
+        self.buffer = [0; SECTOR_SIZE];
+        self.file.seek(SeekFrom::Start(self.sector as u64 * SECTOR_SIZE as u64))?;
+
+        let mut filled = 0;
+
+        while filled < SECTOR_SIZE {
+            match self.file.read(&mut self.buffer[filled..])? {
+                0 => break,
+                count => filled += count
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_sector(&mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.sector as u64 * SECTOR_SIZE as u64))?;
+        self.file.write_all(&self.buffer)?;
+        self.file.flush()
+    }
+}
+
+impl Device for Disk {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            DISK_DATA => {
+                let value = self.buffer[self.index as usize];
+                self.index = self.index.wrapping_add(1);
+                value
+            },
+            _ => self.peek(offset)
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            DISK_SECTOR_LOW => self.sector = (self.sector & 0xFF00) | value as u16,
+            DISK_SECTOR_HIGH => self.sector = (self.sector & 0x00FF) | ((value as u16) << 0x8),
+            DISK_COMMAND => self.execute(value),
+            DISK_DATA => {
+                self.buffer[self.index as usize] = value;
+                self.index = self.index.wrapping_add(1);
+            },
+            DISK_INDEX => self.index = value,
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            DISK_SECTOR_LOW => (self.sector & 0xFF) as u8,
+            DISK_SECTOR_HIGH => (self.sector >> 0x8) as u8,
+            DISK_STATUS => self.status,
+            DISK_DATA => self.buffer[self.index as usize],
+            DISK_INDEX => self.index,
+            _ => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(name: &str, data: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("processor-emulator-{}-{}.img", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn select(disk: &mut Disk, sector: u16) {
+        disk.write(DISK_SECTOR_LOW, sector as u8);
+        disk.write(DISK_SECTOR_HIGH, (sector >> 0x8) as u8);
+    }
+
+    #[test]
+    fn written_sectors_read_back() {
+        let path = image("roundtrip", &[]);
+        let mut disk = Disk::open(&path, true).unwrap();
+
+        select(&mut disk, 2);
+        disk.write(DISK_DATA, 0xAB);
+        disk.write(DISK_DATA, 0xCD);
+        disk.write(DISK_COMMAND, COMMAND_WRITE);
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * SECTOR_SIZE as u64);
+
+        let mut disk = Disk::open(&path, false).unwrap();
+        select(&mut disk, 2);
+        disk.write(DISK_COMMAND, COMMAND_READ);
+
+        assert_eq!(disk.status & (1 << STATUS_ERROR), 0);
+        assert_eq!((disk.read(DISK_DATA), disk.read(DISK_DATA), disk.read(DISK_DATA)), (0xAB, 0xCD, 0x00));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sectors_past_the_end_read_as_zeros() {
+        let path = image("short", &[0x11; SECTOR_SIZE + 1]);
+        let mut disk = Disk::open(&path, false).unwrap();
+
+        select(&mut disk, 1);
+        disk.write(DISK_COMMAND, COMMAND_READ);
+
+        assert_eq!(disk.status & (1 << STATUS_ERROR), 0);
+        assert_eq!(disk.buffer[0], 0x11);
+        assert!(disk.buffer[1..].iter().all(|&byte| byte == 0));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_only_disks_reject_writes() {
+        let path = image("readonly", &[0x22; SECTOR_SIZE]);
+        let mut disk = Disk::open(&path, false).unwrap();
+
+        disk.write(DISK_DATA, 0x33);
+        disk.write(DISK_COMMAND, COMMAND_WRITE);
+
+        assert_eq!(disk.status, (1 << STATUS_ERROR) | (1 << STATUS_READ_ONLY));
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0x22; SECTOR_SIZE]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use crate::bus::{Bus, Device};
+
+pub const DMA_SOURCE_LOW: u16 = 0x0;
+pub const DMA_SOURCE_HIGH: u16 = 0x1;
+pub const DMA_DESTINATION_LOW: u16 = 0x2;
+pub const DMA_DESTINATION_HIGH: u16 = 0x3;
+pub const DMA_LENGTH_LOW: u16 = 0x4;
+pub const DMA_LENGTH_HIGH: u16 = 0x5;
+pub const DMA_CYCLES: u16 = 0x6;
+pub const DMA_CONTROL: u16 = 0x7;
+pub const DMA_STATUS: u16 = 0x8;
+pub const DMA_SIZE: u16 = 0x9;
+
+const CONTROL_START: u8 = 0x00;
+const CONTROL_SOURCE_FIXED: u8 = 0x01;
+const CONTROL_DESTINATION_FIXED: u8 = 0x02;
+const CONTROL_IRQ: u8 = 0x03;
+
+const STATUS_BUSY: u8 = 0x00;
+const STATUS_DONE: u8 = 0x01;
+
+// Block copy engine. Setting START in CONTROL queues a transfer of LENGTH
+// bytes which runs as one burst before the next instruction, stalling the CPU
+// for CYCLES per byte. A fixed source or destination is not incremented, for
+// reading from or writing to a device's data port. DONE stays set until any
+// value is written to STATUS.
+pub struct Dma {
+    pub source: u16,
+    pub destination: u16,
+    pub length: u16,
+    pub cycles_per_byte: u8,
+    pub control: u8,
+    pub status: u8
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            source: 0,
+            destination: 0,
+            length: 0,
+            cycles_per_byte: 1,
+            control: 0,
+            status: 0
+        }
+    }
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Dma {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            DMA_SOURCE_LOW => self.source = (self.source & 0xFF00) | value as u16,
+            DMA_SOURCE_HIGH => self.source = (self.source & 0x00FF) | ((value as u16) << 0x8),
+            DMA_DESTINATION_LOW => self.destination = (self.destination & 0xFF00) | value as u16,
+            DMA_DESTINATION_HIGH => self.destination = (self.destination & 0x00FF) | ((value as u16) << 0x8),
+            DMA_LENGTH_LOW => self.length = (self.length & 0xFF00) | value as u16,
+            DMA_LENGTH_HIGH => self.length = (self.length & 0x00FF) | ((value as u16) << 0x8),
+            DMA_CYCLES => self.cycles_per_byte = value,
+            DMA_CONTROL => {
+                self.control = value;
+
+                if value & (1 << CONTROL_START) != 0 {
+                    self.status |= 1 << STATUS_BUSY;
+                }
+            },
+            DMA_STATUS => self.status &= !(1 << STATUS_DONE),
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            DMA_SOURCE_LOW => (self.source & 0xFF) as u8,
+            DMA_SOURCE_HIGH => (self.source >> 0x8) as u8,
+            DMA_DESTINATION_LOW => (self.destination & 0xFF) as u8,
+            DMA_DESTINATION_HIGH => (self.destination >> 0x8) as u8,
+            DMA_LENGTH_LOW => (self.length & 0xFF) as u8,
+            DMA_LENGTH_HIGH => (self.length >> 0x8) as u8,
+            DMA_CYCLES => self.cycles_per_byte,
+            DMA_CONTROL => self.control,
+            DMA_STATUS => self.status,
+            _ => 0
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control & (1 << CONTROL_IRQ) != 0 && self.status & (1 << STATUS_DONE) != 0
+    }
+
+    fn wants_bus(&self) -> bool {
+        self.status & (1 << STATUS_BUSY) != 0
+    }
+
+    fn master(&mut self, bus: &mut dyn Bus) -> u64 {
+        let length = self.length;
+
+        for _ in 0..length {
+            let value = bus.read(self.source);
+            bus.write(self.destination, value);
+
+            if self.control & (1 << CONTROL_SOURCE_FIXED) == 0 {
+                self.source = self.source.wrapping_add(1);
+            }
+
+            if self.control & (1 << CONTROL_DESTINATION_FIXED) == 0 {
+                self.destination = self.destination.wrapping_add(1);
+            }
+        }
+
+        self.length = 0;
+        self.control &= !(1 << CONTROL_START);
+        self.status &= !(1 << STATUS_BUSY);
+        self.status |= 1 << STATUS_DONE;
+
+        (length as u64 * self.cycles_per_byte as u64).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::SystemBus;
+
+    fn transfer(source: u16, destination: u16, length: u16, control: u8) -> Dma {
+        let mut dma = Dma::new();
+        dma.source = source;
+        dma.destination = destination;
+        dma.length = length;
+        dma.write(DMA_CONTROL, control | (1 << CONTROL_START));
+        dma
+    }
+
+    fn bus_with(address: u16, data: &[u8]) -> SystemBus {
+        let mut bus = SystemBus::new();
+
+        for (i, &byte) in data.iter().enumerate() {
+            bus.write(address.wrapping_add(i as u16), byte);
+        }
+
+        bus
+    }
+
+    #[test]
+    fn copies_a_block_and_reports_done() {
+        let mut bus = bus_with(0x1000, &[1, 2, 3, 4]);
+        let mut dma = transfer(0x1000, 0x2000, 4, 0);
+        dma.cycles_per_byte = 3;
+
+        assert!(dma.wants_bus());
+        assert_eq!(dma.master(&mut bus), 12);
+
+        assert_eq!((0..4).map(|i| bus.peek(0x2000 + i)).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(bus.peek(0x2004), 0);
+        assert_eq!((dma.source, dma.destination, dma.length), (0x1004, 0x2004, 0));
+        assert_eq!(dma.status, 1 << STATUS_DONE);
+        assert!(!dma.wants_bus());
+    }
+
+    #[test]
+    fn zero_length_transfer_completes_without_copying() {
+        let mut bus = bus_with(0x1000, &[7]);
+        let mut dma = transfer(0x1000, 0x2000, 0, 0);
+
+        assert_eq!(dma.master(&mut bus), 1);
+        assert_eq!(bus.peek(0x2000), 0);
+        assert_eq!(dma.status, 1 << STATUS_DONE);
+    }
+
+    #[test]
+    fn addresses_wrap_at_the_top_of_memory() {
+        let mut bus = bus_with(0xFFFF, &[5, 6]);
+        let mut dma = transfer(0xFFFF, 0x3000, 2, 0);
+
+        dma.master(&mut bus);
+
+        assert_eq!((bus.peek(0x3000), bus.peek(0x3001)), (5, 6));
+        assert_eq!(dma.source, 0x0001);
+    }
+
+    #[test]
+    fn fixed_addresses_are_not_incremented() {
+        let mut bus = bus_with(0x1000, &[1, 2, 3]);
+        let mut dma = transfer(0x1000, 0x2000, 3, 1 << CONTROL_DESTINATION_FIXED);
+
+        dma.master(&mut bus);
+
+        assert_eq!((bus.peek(0x2000), bus.peek(0x2001)), (3, 0));
+        assert_eq!(dma.destination, 0x2000);
+
+        let mut dma = transfer(0x1000, 0x2000, 3, 1 << CONTROL_SOURCE_FIXED);
+        dma.master(&mut bus);
+
+        assert_eq!((bus.peek(0x2000), bus.peek(0x2001), bus.peek(0x2002)), (1, 1, 1));
+    }
+
+    #[test]
+    fn done_raises_irq_until_status_is_written() {
+        let mut bus = SystemBus::new();
+        let mut dma = transfer(0x1000, 0x2000, 1, 1 << CONTROL_IRQ);
+
+        assert!(!dma.irq());
+        dma.master(&mut bus);
+        assert!(dma.irq());
+
+        dma.write(DMA_STATUS, 0);
+        assert!(!dma.irq());
+    }
+}
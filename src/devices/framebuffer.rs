@@ -0,0 +1,324 @@
+use crate::bus::Device;
+
+pub const FRAMEBUFFER_FRAME: u16 = 0x0;
+pub const FRAMEBUFFER_PALETTE: u16 = 0x10;
+pub const FRAMEBUFFER_PIXELS: u16 = 0x40;
+
+pub const FRAMEBUFFER_WIDTH: usize = 64;
+pub const FRAMEBUFFER_HEIGHT: usize = 64;
+
+const DEFAULT_PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0x00, 0x00, 0xAA], [0x00, 0xAA, 0x00], [0x00, 0xAA, 0xAA],
+    [0xAA, 0x00, 0x00], [0xAA, 0x00, 0xAA], [0xAA, 0x55, 0x00], [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55], [0x55, 0x55, 0xFF], [0x55, 0xFF, 0x55], [0x55, 0xFF, 0xFF],
+    [0xFF, 0x55, 0x55], [0xFF, 0x55, 0xFF], [0xFF, 0xFF, 0x55], [0xFF, 0xFF, 0xFF]
+];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PixelFormat {
+    // 8 pixels per byte, most significant bit leftmost, drawn with palette
+    // entries 0 and 15.
+    Mono,
+    // 2 pixels per byte, high nibble leftmost, indexing the palette.
+    Palette4
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ImageFormat {
+    Ppm,
+    Png
+}
+
+impl ImageFormat {
+    pub fn from_file_name(file_name: &str) -> ImageFormat {
+        if file_name.to_lowercase().ends_with(".png") { ImageFormat::Png } else { ImageFormat::Ppm }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png"
+        }
+    }
+}
+
+struct Recording {
+    prefix: String,
+    format: ImageFormat,
+    frame: u32
+}
+
+// Pixel framebuffer with a 16 entry RGB palette. Writing FRAME marks the end
+// of a frame; while recording, each frame is written to <prefix>_NNNN.<ext>.
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub palette: [[u8; 3]; 16],
+    pub pixels: Vec<u8>,
+    recording: Option<Recording>
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Framebuffer {
+        let pixels_per_byte = match format {
+            PixelFormat::Mono => 8,
+            PixelFormat::Palette4 => 2
+        };
+
+        Framebuffer {
+            width,
+            height,
+            format,
+            palette: DEFAULT_PALETTE,
+            pixels: vec![0; (width * height).div_ceil(pixels_per_byte)],
+            recording: None
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        FRAMEBUFFER_PIXELS + self.pixels.len() as u16
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let index = y * self.width + x;
+
+        match self.format {
+            PixelFormat::Mono => {
+                if self.pixels[index / 8] & (0x80 >> (index % 8)) != 0 { 15 } else { 0 }
+            },
+            PixelFormat::Palette4 => {
+                let byte = self.pixels[index / 2];
+                if index.is_multiple_of(2) { byte >> 4 } else { byte & 0xF }
+            }
+        }
+    }
+
+    pub fn rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rgb.extend_from_slice(&self.palette[self.pixel(x, y) as usize]);
+            }
+        }
+
+        rgb
+    }
+
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        image.extend(self.rgb());
+        image
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let rgb = self.rgb();
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+
+        for row in rgb.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut image = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+        png_chunk(&mut image, b"IHDR", &header);
+        png_chunk(&mut image, b"IDAT", &zlib_stored(&raw));
+        png_chunk(&mut image, b"IEND", &[]);
+        image
+    }
+
+    pub fn export(&self, file_name: &str, format: ImageFormat) -> std::io::Result<()> {
+        let image = match format {
+            ImageFormat::Ppm => self.to_ppm(),
+            ImageFormat::Png => self.to_png()
+        };
+
+        std::fs::write(file_name, image)
+    }
+
+    pub fn start_recording(&mut self, prefix: &str, format: ImageFormat) {
+        self.recording = Some(Recording {
+            prefix: prefix.to_string(),
+            format,
+            frame: 0
+        });
+    }
+
+    // Returns how many frames were written.
+    pub fn stop_recording(&mut self) -> u32 {
+        self.recording.take().map_or(0, |recording| recording.frame)
+    }
+
+    fn end_frame(&mut self) {
+        let (file_name, format) = match &mut self.recording {
+            Some(recording) => {
+                let file_name = format!("{}_{:04}.{}", recording.prefix, recording.frame, recording.format.extension());
+                recording.frame += 1;
+                (file_name, recording.format)
+            },
+            None => return
+        };
+
+        if let Err(error) = self.export(&file_name, format) {
+            println!("Problem writing frame {}: {}", file_name, error);
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            FRAMEBUFFER_FRAME => self.end_frame(),
+            _ if offset >= FRAMEBUFFER_PIXELS => {
+                if let Some(byte) = self.pixels.get_mut((offset - FRAMEBUFFER_PIXELS) as usize) {
+                    *byte = value;
+                }
+            },
+            _ if offset >= FRAMEBUFFER_PALETTE => {
+                let index = (offset - FRAMEBUFFER_PALETTE) as usize;
+                self.palette[index / 3][index % 3] = value;
+            },
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            _ if offset >= FRAMEBUFFER_PIXELS => {
+                self.pixels.get((offset - FRAMEBUFFER_PIXELS) as usize).copied().unwrap_or(0)
+            },
+            _ if offset >= FRAMEBUFFER_PALETTE => {
+                let index = (offset - FRAMEBUFFER_PALETTE) as usize;
+                self.palette[index / 3][index % 3]
+            },
+            _ => 0
+        }
+    }
+}
+
+fn png_chunk(image: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = image.len();
+    image.extend_from_slice(chunk_type);
+    image.extend_from_slice(data);
+
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Uncompressed deflate stream; the images are tiny so compression is not
+// worth a dependency.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = data.chunks(0xFFFF).collect();
+
+    for (i, block) in blocks.iter().enumerate() {
+        let last = if i == blocks.len() - 1 { 1 } else { 0 };
+        let length = block.len() as u16;
+
+        stream.push(last);
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    if blocks.is_empty() {
+        stream.extend_from_slice(&[1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mono_pixels_are_most_significant_bit_first() {
+        let mut framebuffer = Framebuffer::new(16, 2, PixelFormat::Mono);
+        framebuffer.write(FRAMEBUFFER_PIXELS, 0x80);
+        framebuffer.write(FRAMEBUFFER_PIXELS + 3, 0x01);
+
+        assert_eq!(framebuffer.size(), FRAMEBUFFER_PIXELS + 4);
+        assert_eq!(framebuffer.pixel(0, 0), 15);
+        assert_eq!(framebuffer.pixel(1, 0), 0);
+        assert_eq!(framebuffer.pixel(15, 1), 15);
+    }
+
+    #[test]
+    fn palette4_pixels_are_high_nibble_first() {
+        let mut framebuffer = Framebuffer::new(4, 1, PixelFormat::Palette4);
+        framebuffer.write(FRAMEBUFFER_PIXELS, 0x3C);
+        framebuffer.write(FRAMEBUFFER_PALETTE + 3 * 3, 0x12);
+
+        assert_eq!(framebuffer.size(), FRAMEBUFFER_PIXELS + 2);
+        assert_eq!((framebuffer.pixel(0, 0), framebuffer.pixel(1, 0)), (3, 12));
+        assert_eq!(&framebuffer.rgb()[0..3], &[0x12, 0xAA, 0xAA]);
+    }
+
+    #[test]
+    fn writes_past_the_pixels_are_ignored() {
+        let mut framebuffer = Framebuffer::new(8, 1, PixelFormat::Mono);
+        framebuffer.write(FRAMEBUFFER_PIXELS + 1, 0xFF);
+
+        assert_eq!(framebuffer.peek(FRAMEBUFFER_PIXELS + 1), 0);
+    }
+
+    #[test]
+    fn ppm_has_a_header_and_rgb_triples() {
+        let framebuffer = Framebuffer::new(2, 2, PixelFormat::Mono);
+        let image = framebuffer.to_ppm();
+        let header = b"P6\n2 2\n255\n";
+
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(image.len(), header.len() + 2 * 2 * 3);
+    }
+
+    #[test]
+    fn png_checksums_match_known_values() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+
+        let image = Framebuffer::new(2, 2, PixelFormat::Mono).to_png();
+
+        assert_eq!(&image[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+        assert_eq!(&image[image.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+
+use crate::bus::Device;
+use crate::devices::terminal::HostInput;
+
+pub const KEYBOARD_DATA: u16 = 0x0;
+pub const KEYBOARD_STATUS: u16 = 0x1;
+pub const KEYBOARD_CONTROL: u16 = 0x2;
+pub const KEYBOARD_SIZE: u16 = 0x3;
+
+const STATUS_KEY_READY: u8 = 0x00;
+
+const CONTROL_IRQ: u8 = 0x00;
+
+pub enum KeyboardInput {
+    // Keys typed at the host terminal, read only while a RawTerminal is held.
+    Host(HostInput),
+    // Keys delivered once the emulated cycle count reaches their timestamp.
+    Script(VecDeque<(u64, u8)>)
+}
+
+// Keyboard with a queue of key codes. Reading DATA takes the next key (0 when
+// none is waiting).
+pub struct Keyboard {
+    input: KeyboardInput,
+    keys: VecDeque<u8>,
+    control: u8,
+    cycle: u64
+}
+
+impl Keyboard {
+    pub fn host() -> Keyboard {
+        Keyboard::new(KeyboardInput::Host(HostInput::new()))
+    }
+
+    pub fn from_script(path: &str) -> std::io::Result<Keyboard> {
+        Keyboard::parse_script(&std::fs::read_to_string(path)?)
+    }
+
+    // Each line is "<cycle> <key>", where the key is a single character or a
+    // 0x prefixed code. Text after ';' is ignored.
+    fn parse_script(text: &str) -> std::io::Result<Keyboard> {
+        let mut script = VecDeque::new();
+
+        for line in text.lines() {
+            let line = line[0..line.find(';').unwrap_or(line.len())].trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid script line: {}", line));
+
+            let (cycle, key) = line.split_once(' ').ok_or_else(invalid)?;
+            let cycle = cycle.parse::<u64>().map_err(|_| invalid())?;
+            let key = match key.trim() {
+                key if key.starts_with("0x") && key.len() > 2 => u8::from_str_radix(&key[2..], 16).map_err(|_| invalid())?,
+                key if key.len() == 1 => key.as_bytes()[0],
+                _ => return Err(invalid())
+            };
+
+            script.push_back((cycle, key));
+        }
+
+        script.make_contiguous().sort_by_key(|&(cycle, _)| cycle);
+
+        Ok(Keyboard::new(KeyboardInput::Script(script)))
+    }
+
+    fn new(input: KeyboardInput) -> Keyboard {
+        Keyboard {
+            input,
+            keys: VecDeque::new(),
+            control: 0,
+            cycle: 0
+        }
+    }
+
+    pub fn is_host(&self) -> bool {
+        matches!(self.input, KeyboardInput::Host(_))
+    }
+
+    pub fn push_keys(&mut self, keys: &[u8]) {
+        self.keys.extend(keys);
+    }
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            KEYBOARD_DATA => self.keys.pop_front().unwrap_or(0),
+            _ => self.peek(offset)
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if offset == KEYBOARD_CONTROL {
+            self.control = value;
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            KEYBOARD_DATA => self.keys.front().copied().unwrap_or(0),
+            KEYBOARD_STATUS => if self.keys.is_empty() { 0 } else { 1 << STATUS_KEY_READY },
+            KEYBOARD_CONTROL => self.control,
+            _ => 0
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control & (1 << CONTROL_IRQ) != 0 && !self.keys.is_empty()
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycle += cycles;
+
+        match &mut self.input {
+            KeyboardInput::Host(host) => host.poll(&mut self.keys),
+            KeyboardInput::Script(script) => {
+                while script.front().is_some_and(|&(cycle, _)| cycle <= self.cycle) {
+                    self.keys.extend(script.pop_front().map(|(_, key)| key));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_keys_arrive_at_their_cycle() {
+        let mut keyboard = Keyboard::parse_script("; boot\n20 b\n10 a ; first\n\n30 0x0D\n").unwrap();
+
+        keyboard.tick(9);
+        assert_eq!(keyboard.peek(KEYBOARD_STATUS), 0);
+
+        keyboard.tick(11);
+        assert_eq!(keyboard.peek(KEYBOARD_STATUS), 1 << STATUS_KEY_READY);
+        assert_eq!((keyboard.read(KEYBOARD_DATA), keyboard.read(KEYBOARD_DATA)), (b'a', b'b'));
+        assert_eq!(keyboard.read(KEYBOARD_DATA), 0);
+
+        keyboard.tick(10);
+        assert_eq!(keyboard.read(KEYBOARD_DATA), 0x0D);
+    }
+
+    #[test]
+    fn invalid_script_lines_are_rejected() {
+        assert!(Keyboard::parse_script("soon a\n").is_err());
+        assert!(Keyboard::parse_script("10 ab\n").is_err());
+        assert!(Keyboard::parse_script("10 0xZZ\n").is_err());
+    }
+
+    #[test]
+    fn irq_is_raised_while_keys_are_waiting() {
+        let mut keyboard = Keyboard::parse_script("").unwrap();
+        keyboard.write(KEYBOARD_CONTROL, 1 << CONTROL_IRQ);
+        assert!(!keyboard.irq());
+
+        keyboard.push_keys(b"x");
+        assert!(keyboard.irq());
+
+        keyboard.read(KEYBOARD_DATA);
+        assert!(!keyboard.irq());
+    }
+}
@@ -0,0 +1,11 @@
+pub mod disk;
+pub mod dma;
+pub mod framebuffer;
+pub mod keyboard;
+pub mod output;
+pub mod random;
+pub mod rtc;
+pub mod terminal;
+pub mod text;
+pub mod timer;
+pub mod uart;
@@ -0,0 +1,152 @@
+use crate::bus::Device;
+
+pub const OUTPUT_VALUE: u16 = 0x0;
+pub const OUTPUT_MODE: u16 = 0x1;
+pub const OUTPUT_SIZE: u16 = 0x2;
+
+pub const DISPLAY_DIGITS: usize = 4;
+
+// Segment patterns for 0-F, bit 0 is segment a through bit 6 for segment g.
+pub const SEGMENT_DIGITS: [u8; 16] = [
+    0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07,
+    0x7F, 0x6F, 0x77, 0x7C, 0x39, 0x5E, 0x79, 0x71
+];
+pub const SEGMENT_MINUS: u8 = 0x40;
+pub const SEGMENT_BLANK: u8 = 0x00;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DisplayMode {
+    Unsigned,
+    Signed,
+    Hex
+}
+
+impl DisplayMode {
+    pub fn from_register(value: u8) -> DisplayMode {
+        match value {
+            1 => DisplayMode::Signed,
+            2 => DisplayMode::Hex,
+            _ => DisplayMode::Unsigned
+        }
+    }
+
+    pub fn to_register(self) -> u8 {
+        match self {
+            DisplayMode::Unsigned => 0,
+            DisplayMode::Signed => 1,
+            DisplayMode::Hex => 2
+        }
+    }
+}
+
+// Output register driving a row of 7-segment digits. Every value latched by
+// the program is kept in order so tests can check the whole output.
+pub struct OutputPort {
+    pub value: u8,
+    pub mode: DisplayMode,
+    history: Vec<u8>
+}
+
+impl OutputPort {
+    pub fn new() -> OutputPort {
+        OutputPort {
+            value: 0,
+            mode: DisplayMode::Unsigned,
+            history: Vec::new()
+        }
+    }
+
+    pub fn history(&self) -> &[u8] {
+        &self.history
+    }
+
+    pub fn render(&self) -> String {
+        render_segments(&segments(self.value, self.mode))
+    }
+}
+
+impl Default for OutputPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for OutputPort {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            OUTPUT_VALUE => {
+                self.value = value;
+                self.history.push(value);
+            },
+            OUTPUT_MODE => self.mode = DisplayMode::from_register(value),
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            OUTPUT_VALUE => self.value,
+            OUTPUT_MODE => self.mode.to_register(),
+            _ => 0
+        }
+    }
+}
+
+// Segment patterns for each digit, most significant first, with leading
+// zeros blanked in the decimal modes.
+pub fn segments(value: u8, mode: DisplayMode) -> [u8; DISPLAY_DIGITS] {
+    let mut digits = [SEGMENT_BLANK; DISPLAY_DIGITS];
+
+    let (magnitude, negative, base) = match mode {
+        DisplayMode::Unsigned => (value as u16, false, 10),
+        DisplayMode::Signed => ((value as i8).unsigned_abs() as u16, (value as i8) < 0, 10),
+        DisplayMode::Hex => (value as u16, false, 16)
+    };
+
+    let mut remaining = magnitude;
+    let mut position = DISPLAY_DIGITS;
+
+    loop {
+        position -= 1;
+        digits[position] = SEGMENT_DIGITS[(remaining % base) as usize];
+        remaining /= base;
+
+        if (mode == DisplayMode::Hex && position == DISPLAY_DIGITS - 2) || (mode != DisplayMode::Hex && remaining == 0) {
+            break;
+        }
+    }
+
+    if negative {
+        digits[position - 1] = SEGMENT_MINUS;
+    }
+
+    digits
+}
+
+pub fn render_segments(digits: &[u8]) -> String {
+    let lit = |digit: u8, segment: u8, c: char| if digit & (1 << segment) != 0 { c } else { ' ' };
+
+    let mut lines = [String::new(), String::new(), String::new()];
+
+    for &digit in digits {
+        lines[0].push(' ');
+        lines[0].push(lit(digit, 0, '_'));
+        lines[0].push_str("  ");
+
+        lines[1].push(lit(digit, 5, '|'));
+        lines[1].push(lit(digit, 6, '_'));
+        lines[1].push(lit(digit, 1, '|'));
+        lines[1].push(' ');
+
+        lines[2].push(lit(digit, 4, '|'));
+        lines[2].push(lit(digit, 3, '_'));
+        lines[2].push(lit(digit, 2, '|'));
+        lines[2].push(' ');
+    }
+
+    lines.iter().map(|line| line.trim_end()).collect::<Vec<_>>().join("\n")
+}
@@ -0,0 +1,119 @@
+use crate::bus::Device;
+
+pub const RANDOM_DATA: u16 = 0x0;
+pub const RANDOM_SEED: u16 = 0x1;
+pub const RANDOM_SIZE: u16 = 0x5;
+
+// Seeded xorshift generator. Every read of DATA returns a new byte. The four
+// SEED bytes (little-endian) are latched as they are written, and writing the
+// high byte restarts the sequence from the latched seed, so runs with the
+// same seed are reproducible.
+pub struct Random {
+    state: u32,
+    seed: u32
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Random {
+        let mut random = Random { state: 0, seed: 0 };
+        random.seed(seed);
+        random
+    }
+
+    pub fn seed(&mut self, seed: u32) {
+        self.seed = seed;
+
+        // Xorshift never leaves zero, so treat it as an arbitrary other seed.
+        self.state = if seed == 0 { 0x2545F491 } else { seed };
+    }
+
+    fn next_state(state: u32) -> u32 {
+        let mut state = state;
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    }
+}
+
+impl Device for Random {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            RANDOM_DATA => {
+                self.state = Random::next_state(self.state);
+                (self.state >> 24) as u8
+            },
+            _ => self.peek(offset)
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if (RANDOM_SEED..RANDOM_SIZE).contains(&offset) {
+            let shift = (offset - RANDOM_SEED) * 8;
+            self.seed = (self.seed & !(0xFF << shift)) | ((value as u32) << shift);
+
+            if offset == RANDOM_SIZE - 1 {
+                self.seed(self.seed);
+            }
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            RANDOM_DATA => (Random::next_state(self.state) >> 24) as u8,
+            _ if (RANDOM_SEED..RANDOM_SIZE).contains(&offset) => (self.seed >> ((offset - RANDOM_SEED) * 8)) as u8,
+            _ => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seed_from_guest(random: &mut Random, seed: u32) {
+        for (i, byte) in seed.to_le_bytes().into_iter().enumerate() {
+            random.write(RANDOM_SEED + i as u16, byte);
+        }
+    }
+
+    fn sequence(random: &mut Random) -> Vec<u8> {
+        (0..16).map(|_| random.read(RANDOM_DATA)).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_sequence_regardless_of_state() {
+        let mut fresh = Random::new(1);
+        let mut used = Random::new(0xABCD0000);
+        sequence(&mut used);
+
+        seed_from_guest(&mut fresh, 0x12340000);
+        seed_from_guest(&mut used, 0x12340000);
+
+        let expected = sequence(&mut Random::new(0x12340000));
+
+        assert_eq!(sequence(&mut fresh), expected);
+        assert_eq!(sequence(&mut used), expected);
+    }
+
+    #[test]
+    fn seed_is_applied_on_the_high_byte() {
+        let mut random = Random::new(0x12345678);
+        let expected = sequence(&mut Random::new(0x12345678));
+
+        random.write(RANDOM_SEED, 0xEF);
+        random.write(RANDOM_SEED + 1, 0xBE);
+
+        assert_eq!(random.peek(RANDOM_SEED), 0xEF);
+        assert_eq!(sequence(&mut random), expected);
+    }
+
+    #[test]
+    fn zero_seed_is_reproducible() {
+        let mut random = Random::new(0xABCD0000);
+        seed_from_guest(&mut random, 0);
+
+        assert_eq!(sequence(&mut random), sequence(&mut Random::new(0)));
+        assert!(sequence(&mut random).iter().any(|&byte| byte != 0));
+    }
+}
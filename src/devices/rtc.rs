@@ -0,0 +1,85 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bus::Device;
+
+pub const RTC_SECOND: u16 = 0x0;
+pub const RTC_MINUTE: u16 = 0x1;
+pub const RTC_HOUR: u16 = 0x2;
+pub const RTC_DAY: u16 = 0x3;
+pub const RTC_MONTH: u16 = 0x4;
+pub const RTC_YEAR_LOW: u16 = 0x5;
+pub const RTC_YEAR_HIGH: u16 = 0x6;
+pub const RTC_WEEKDAY: u16 = 0x7;
+pub const RTC_SIZE: u16 = 0x8;
+
+// Real-time clock reporting UTC. Reading SECOND latches the whole time so the
+// other fields stay consistent; read it first. A fixed time replaces the host
+// clock for deterministic runs.
+pub struct Rtc {
+    pub fixed: Option<u64>,
+    latched: [u8; RTC_SIZE as usize]
+}
+
+impl Rtc {
+    pub fn new(fixed: Option<u64>) -> Rtc {
+        let mut rtc = Rtc {
+            fixed,
+            latched: [0; RTC_SIZE as usize]
+        };
+
+        rtc.latch();
+        rtc
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.fixed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs()))
+    }
+
+    fn latch(&mut self) {
+        self.latched = fields(self.timestamp());
+    }
+}
+
+impl Device for Rtc {
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset == RTC_SECOND {
+            self.latch();
+        }
+
+        self.peek(offset)
+    }
+
+    fn write(&mut self, _offset: u16, _value: u8) {}
+
+    fn peek(&self, offset: u16) -> u8 {
+        self.latched.get(offset as usize).copied().unwrap_or(0)
+    }
+}
+
+// Register values for a Unix timestamp. Weekday 0 is Sunday.
+fn fields(timestamp: u64) -> [u8; RTC_SIZE as usize] {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+
+    [
+        (seconds % 60) as u8,
+        (seconds / 60 % 60) as u8,
+        (seconds / 3600) as u8,
+        day as u8,
+        month as u8,
+        (year & 0xFF) as u8,
+        (year >> 0x8) as u8,
+        (days + 4).rem_euclid(7) as u8
+    ]
+}
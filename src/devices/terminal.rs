@@ -0,0 +1,72 @@
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Read};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+// Bytes typed at the host terminal, read only while a RawTerminal is held.
+// Devices poll on every tick, but stdin is only read once per POLL_INTERVAL
+// of wall-clock time so an unthrottled run isn't paced by the read calls.
+#[derive(Default)]
+pub struct HostInput {
+    last_poll: Option<Instant>
+}
+
+impl HostInput {
+    pub fn new() -> HostInput {
+        HostInput { last_poll: None }
+    }
+
+    pub fn poll(&mut self, queue: &mut VecDeque<u8>) {
+        if !RAW_MODE.load(Ordering::Relaxed) || self.last_poll.is_some_and(|last| last.elapsed() < POLL_INTERVAL) {
+            return;
+        }
+
+        self.last_poll = Some(Instant::now());
+
+        let mut buffer = [0; 16];
+
+        if let Ok(count) = std::io::stdin().read(&mut buffer) {
+            queue.extend(&buffer[..count]);
+        }
+    }
+}
+
+// Puts the host terminal into unbuffered, no-echo mode with non-blocking
+// reads for as long as it is held. Signals are left enabled so Ctrl-C still
+// works.
+pub struct RawTerminal {
+    saved: String
+}
+
+impl RawTerminal {
+    pub fn enable() -> std::io::Result<RawTerminal> {
+        if !std::io::stdin().is_terminal() {
+            return Err(std::io::Error::other("stdin is not a terminal"));
+        }
+
+        let saved = Command::new("stty").arg("-g").stdin(std::process::Stdio::inherit()).output()?;
+        let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+
+        Command::new("stty")
+            .args(["-icanon", "-echo", "min", "0", "time", "0"])
+            .stdin(std::process::Stdio::inherit())
+            .status()?;
+
+        RAW_MODE.store(true, Ordering::Relaxed);
+
+        Ok(RawTerminal { saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        RAW_MODE.store(false, Ordering::Relaxed);
+
+        let _ = Command::new("stty").arg(&self.saved).stdin(std::process::Stdio::inherit()).status();
+    }
+}
@@ -0,0 +1,246 @@
+use crate::bus::Device;
+
+pub const TEXT_CURSOR_X: u16 = 0x0;
+pub const TEXT_CURSOR_Y: u16 = 0x1;
+pub const TEXT_PUTC: u16 = 0x2;
+pub const TEXT_COMMAND: u16 = 0x3;
+pub const TEXT_CONTROL: u16 = 0x4;
+pub const TEXT_SCREEN: u16 = 0x10;
+
+pub const TEXT_WIDTH: usize = 40;
+pub const TEXT_HEIGHT: usize = 25;
+
+const COMMAND_SCROLL: u8 = 0x01;
+const COMMAND_CLEAR: u8 = 0x02;
+
+const CONTROL_CURSOR: u8 = 0x00;
+
+// Text-mode display. Character cells start at TEXT_SCREEN, row by row, and
+// are followed by one attribute byte per cell when attributes are enabled
+// (low nibble foreground, high nibble background). Writing PUTC prints at the
+// cursor and advances it, scrolling at the bottom of the screen.
+pub struct TextDisplay {
+    pub width: usize,
+    pub height: usize,
+    pub cursor_x: u8,
+    pub cursor_y: u8,
+    pub control: u8,
+    pub characters: Vec<u8>,
+    pub attributes: Option<Vec<u8>>
+}
+
+impl TextDisplay {
+    pub fn new(width: usize, height: usize, attributes: bool) -> TextDisplay {
+        TextDisplay {
+            width,
+            height,
+            cursor_x: 0,
+            cursor_y: 0,
+            control: 0,
+            characters: vec![b' '; width * height],
+            attributes: if attributes { Some(vec![0x07; width * height]) } else { None }
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        let cells = self.width * self.height;
+        let planes = if self.attributes.is_some() { 2 } else { 1 };
+
+        TEXT_SCREEN + (cells * planes) as u16
+    }
+
+    pub fn scroll(&mut self) {
+        self.characters.drain(0..self.width);
+        self.characters.extend(std::iter::repeat_n(b' ', self.width));
+
+        if let Some(attributes) = &mut self.attributes {
+            let last = attributes[attributes.len() - 1];
+            attributes.drain(0..self.width);
+            attributes.extend(std::iter::repeat_n(last, self.width));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.characters.fill(b' ');
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    // Plain text of the screen for headless runs and tests, one line per row.
+    pub fn dump_text(&self) -> String {
+        self.characters
+            .chunks(self.width)
+            .map(|row| row.iter().map(|&c| printable(c)).collect::<String>().trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Screen with a border, colored with ANSI escapes when attributes are
+    // enabled and the cursor shown in inverse video.
+    pub fn render(&self) -> String {
+        let border = format!("+{}+", "-".repeat(self.width));
+        let cursor = (self.cursor_y as usize * self.width) + self.cursor_x as usize;
+        let mut output = border.clone();
+
+        for row in 0..self.height {
+            output.push_str("\n|");
+
+            for column in 0..self.width {
+                let cell = row * self.width + column;
+                let mut styles = Vec::new();
+
+                if let Some(attributes) = &self.attributes {
+                    styles.push(ANSI_FOREGROUND[(attributes[cell] & 0xF) as usize].to_string());
+                    styles.push(ANSI_BACKGROUND[(attributes[cell] >> 4) as usize].to_string());
+                }
+
+                if cell == cursor && self.control & (1 << CONTROL_CURSOR) != 0 {
+                    styles.push("7".to_string());
+                }
+
+                if styles.is_empty() {
+                    output.push(printable(self.characters[cell]));
+                } else {
+                    output.push_str(&format!("\x1B[{}m{}\x1B[0m", styles.join(";"), printable(self.characters[cell])));
+                }
+            }
+
+            output.push('|');
+        }
+
+        output.push('\n');
+        output.push_str(&border);
+        output
+    }
+
+    fn put_char(&mut self, value: u8) {
+        match value {
+            b'\n' => {
+                self.cursor_x = 0;
+                self.cursor_y += 1;
+            },
+            b'\r' => self.cursor_x = 0,
+            _ => {
+                let cell = (self.cursor_y as usize * self.width) + self.cursor_x as usize;
+
+                if cell < self.characters.len() {
+                    self.characters[cell] = value;
+                }
+
+                self.cursor_x += 1;
+
+                if self.cursor_x as usize >= self.width {
+                    self.cursor_x = 0;
+                    self.cursor_y += 1;
+                }
+            }
+        }
+
+        if self.cursor_y as usize >= self.height {
+            self.scroll();
+            self.cursor_y = (self.height - 1) as u8;
+        }
+    }
+}
+
+impl Device for TextDisplay {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        let cells = self.characters.len();
+
+        match offset {
+            // Out of range positions are held at the last column or row.
+            TEXT_CURSOR_X => self.cursor_x = value.min((self.width - 1) as u8),
+            TEXT_CURSOR_Y => self.cursor_y = value.min((self.height - 1) as u8),
+            TEXT_PUTC => self.put_char(value),
+            TEXT_COMMAND => match value {
+                COMMAND_SCROLL => self.scroll(),
+                COMMAND_CLEAR => self.clear(),
+                _ => {}
+            },
+            TEXT_CONTROL => self.control = value,
+            _ if offset >= TEXT_SCREEN => {
+                let index = (offset - TEXT_SCREEN) as usize;
+
+                if index < cells {
+                    self.characters[index] = value;
+                } else if let Some(attributes) = &mut self.attributes {
+                    attributes[index - cells] = value;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        let cells = self.characters.len();
+
+        match offset {
+            TEXT_CURSOR_X => self.cursor_x,
+            TEXT_CURSOR_Y => self.cursor_y,
+            TEXT_CONTROL => self.control,
+            _ if offset >= TEXT_SCREEN => {
+                let index = (offset - TEXT_SCREEN) as usize;
+
+                if index < cells {
+                    self.characters[index]
+                } else {
+                    self.attributes.as_ref().map_or(0, |attributes| attributes[index - cells])
+                }
+            },
+            _ => 0
+        }
+    }
+}
+
+const ANSI_FOREGROUND: [u8; 16] = [30, 34, 32, 36, 31, 35, 33, 37, 90, 94, 92, 96, 91, 95, 93, 97];
+const ANSI_BACKGROUND: [u8; 16] = [40, 44, 42, 46, 41, 45, 43, 47, 100, 104, 102, 106, 101, 105, 103, 107];
+
+fn printable(value: u8) -> char {
+    if value.is_ascii_graphic() { value as char } else { ' ' }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_writes_are_clamped_to_the_screen() {
+        let mut display = TextDisplay::new(TEXT_WIDTH, TEXT_HEIGHT, false);
+
+        display.write(TEXT_CURSOR_X, 0xFF);
+        display.write(TEXT_CURSOR_Y, 0xFF);
+
+        assert_eq!(display.peek(TEXT_CURSOR_X), (TEXT_WIDTH - 1) as u8);
+        assert_eq!(display.peek(TEXT_CURSOR_Y), (TEXT_HEIGHT - 1) as u8);
+    }
+
+    #[test]
+    fn putc_at_the_last_cell_wraps_and_scrolls() {
+        let mut display = TextDisplay::new(TEXT_WIDTH, TEXT_HEIGHT, false);
+
+        display.write(TEXT_CURSOR_X, 0xFF);
+        display.write(TEXT_CURSOR_Y, 0xFF);
+        display.write(TEXT_PUTC, b'X');
+
+        assert_eq!((display.cursor_x, display.cursor_y), (0, (TEXT_HEIGHT - 1) as u8));
+        assert_eq!(display.characters[(TEXT_HEIGHT - 1) * TEXT_WIDTH - 1], b'X');
+        assert_eq!(display.characters[TEXT_HEIGHT * TEXT_WIDTH - 1], b' ');
+    }
+
+    #[test]
+    fn newline_on_the_last_row_scrolls() {
+        let mut display = TextDisplay::new(TEXT_WIDTH, TEXT_HEIGHT, false);
+
+        display.write(TEXT_SCREEN, b'A');
+        display.write(TEXT_SCREEN + TEXT_WIDTH as u16, b'B');
+        display.write(TEXT_CURSOR_Y, (TEXT_HEIGHT - 1) as u8);
+        display.write(TEXT_PUTC, b'\n');
+
+        assert_eq!(display.cursor_y, (TEXT_HEIGHT - 1) as u8);
+        assert_eq!(display.characters[0], b'B');
+    }
+}
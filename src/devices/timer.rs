@@ -0,0 +1,206 @@
+use crate::bus::Device;
+
+pub const TIMER_RELOAD_LOW: u16 = 0x0;
+pub const TIMER_RELOAD_HIGH: u16 = 0x1;
+pub const TIMER_COUNTER_LOW: u16 = 0x2;
+pub const TIMER_COUNTER_HIGH: u16 = 0x3;
+pub const TIMER_PRESCALER: u16 = 0x4;
+pub const TIMER_CONTROL: u16 = 0x5;
+pub const TIMER_STATUS: u16 = 0x6;
+pub const TIMER_SIZE: u16 = 0x7;
+
+const CONTROL_ENABLE: u8 = 0x00;
+const CONTROL_PERIODIC: u8 = 0x01;
+const CONTROL_IRQ: u8 = 0x02;
+
+const STATUS_UNDERFLOW: u8 = 0x00;
+
+// Interval timer clocked from emulated cycles. The counter decrements once
+// every PRESCALER + 1 cycles and underflows when it reaches zero, setting the
+// status flag until any value is written to STATUS. Periodic timers reload
+// from RELOAD, one-shot timers stop.
+pub struct Timer {
+    pub reload: u16,
+    pub counter: u16,
+    pub prescaler: u8,
+    pub control: u8,
+    pub status: u8,
+    prescale_count: u64
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            reload: 0,
+            counter: 0,
+            prescaler: 0,
+            control: 0,
+            status: 0,
+            prescale_count: 0
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.control & (1 << CONTROL_ENABLE) != 0
+    }
+
+    fn count(&mut self) {
+        self.counter = self.counter.wrapping_sub(1);
+
+        if self.counter != 0 {
+            return;
+        }
+
+        self.status |= 1 << STATUS_UNDERFLOW;
+
+        if self.control & (1 << CONTROL_PERIODIC) != 0 {
+            self.counter = self.reload;
+        } else {
+            self.control &= !(1 << CONTROL_ENABLE);
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.peek(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            TIMER_RELOAD_LOW => self.reload = (self.reload & 0xFF00) | value as u16,
+            TIMER_RELOAD_HIGH => self.reload = (self.reload & 0x00FF) | ((value as u16) << 0x8),
+            TIMER_COUNTER_LOW => self.counter = (self.counter & 0xFF00) | value as u16,
+            TIMER_COUNTER_HIGH => self.counter = (self.counter & 0x00FF) | ((value as u16) << 0x8),
+            TIMER_PRESCALER => self.prescaler = value,
+            TIMER_CONTROL => {
+                if !self.enabled() && value & (1 << CONTROL_ENABLE) != 0 {
+                    self.counter = self.reload;
+                    self.prescale_count = 0;
+                }
+
+                self.control = value;
+            },
+            TIMER_STATUS => self.status = 0,
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            TIMER_RELOAD_LOW => (self.reload & 0xFF) as u8,
+            TIMER_RELOAD_HIGH => (self.reload >> 0x8) as u8,
+            TIMER_COUNTER_LOW => (self.counter & 0xFF) as u8,
+            TIMER_COUNTER_HIGH => (self.counter >> 0x8) as u8,
+            TIMER_PRESCALER => self.prescaler,
+            TIMER_CONTROL => self.control,
+            TIMER_STATUS => self.status,
+            _ => 0
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control & (1 << CONTROL_IRQ) != 0 && self.status & (1 << STATUS_UNDERFLOW) != 0
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if !self.enabled() {
+            return;
+        }
+
+        self.prescale_count += cycles;
+
+        while self.enabled() && self.prescale_count > self.prescaler as u64 {
+            self.prescale_count -= self.prescaler as u64 + 1;
+            self.count();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(timer: &mut Timer, reload: u16, prescaler: u8, control: u8) {
+        timer.write(TIMER_RELOAD_LOW, reload as u8);
+        timer.write(TIMER_RELOAD_HIGH, (reload >> 0x8) as u8);
+        timer.write(TIMER_PRESCALER, prescaler);
+        timer.write(TIMER_CONTROL, control | (1 << CONTROL_ENABLE));
+    }
+
+    #[test]
+    fn periodic_timer_reloads_on_underflow() {
+        let mut timer = Timer::new();
+        start(&mut timer, 3, 0, 1 << CONTROL_PERIODIC);
+
+        timer.tick(2);
+        assert_eq!(timer.counter, 1);
+        assert_eq!(timer.status, 0);
+
+        timer.tick(1);
+        assert_eq!(timer.counter, 3);
+        assert_eq!(timer.status, 1 << STATUS_UNDERFLOW);
+        assert!(timer.enabled());
+
+        timer.tick(4);
+        assert_eq!(timer.counter, 2);
+    }
+
+    #[test]
+    fn one_shot_timer_stops_on_underflow() {
+        let mut timer = Timer::new();
+        start(&mut timer, 2, 0, 0);
+
+        timer.tick(5);
+
+        assert_eq!(timer.counter, 0);
+        assert_eq!(timer.status, 1 << STATUS_UNDERFLOW);
+        assert!(!timer.enabled());
+    }
+
+    #[test]
+    fn prescaler_divides_the_clock() {
+        let mut timer = Timer::new();
+        start(&mut timer, 10, 3, 0);
+
+        timer.tick(7);
+        assert_eq!(timer.counter, 9);
+
+        timer.tick(1);
+        assert_eq!(timer.counter, 8);
+    }
+
+    #[test]
+    fn irq_follows_the_status_flag_when_enabled() {
+        let mut timer = Timer::new();
+        start(&mut timer, 1, 0, 1 << CONTROL_PERIODIC);
+
+        timer.tick(1);
+        assert!(!timer.irq());
+
+        timer.write(TIMER_CONTROL, (1 << CONTROL_ENABLE) | (1 << CONTROL_PERIODIC) | (1 << CONTROL_IRQ));
+        assert!(timer.irq());
+
+        timer.write(TIMER_STATUS, 0);
+        assert!(!timer.irq());
+
+        timer.tick(1);
+        assert!(timer.irq());
+    }
+
+    #[test]
+    fn enabling_loads_the_counter_from_reload() {
+        let mut timer = Timer::new();
+        timer.write(TIMER_COUNTER_LOW, 0x55);
+        start(&mut timer, 0x1234, 0, 0);
+
+        assert_eq!(timer.peek(TIMER_COUNTER_LOW), 0x34);
+        assert_eq!(timer.peek(TIMER_COUNTER_HIGH), 0x12);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::io::Write;
+
+use crate::bus::Device;
+use crate::devices::terminal::HostInput;
+
+pub const UART_DATA: u16 = 0x0;
+pub const UART_STATUS: u16 = 0x1;
+pub const UART_CONTROL: u16 = 0x2;
+pub const UART_SIZE: u16 = 0x3;
+
+const STATUS_RX_READY: u8 = 0x00;
+const STATUS_TX_READY: u8 = 0x01;
+
+const CONTROL_RX_IRQ: u8 = 0x00;
+
+pub enum UartOutput {
+    Stdout,
+    File(std::fs::File),
+    Buffer(Vec<u8>)
+}
+
+// Serial console. Writing DATA transmits a byte, reading it takes the next
+// received byte (0 when nothing is waiting). Transmission never blocks, so
+// TX_READY is always set. Input comes from push_input(), and from the host
+// terminal while host input is on and a RawTerminal is held.
+pub struct Uart {
+    output: UartOutput,
+    input: VecDeque<u8>,
+    control: u8,
+    host: Option<HostInput>
+}
+
+impl Uart {
+    pub fn new(output: UartOutput) -> Uart {
+        Uart {
+            output,
+            input: VecDeque::new(),
+            control: 0,
+            host: None
+        }
+    }
+
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    pub fn set_host_input(&mut self, enabled: bool) {
+        if enabled != self.host.is_some() {
+            self.host = if enabled { Some(HostInput::new()) } else { None };
+        }
+    }
+
+    pub fn output_buffer(&self) -> Option<&[u8]> {
+        match &self.output {
+            UartOutput::Buffer(buffer) => Some(buffer),
+            _ => None
+        }
+    }
+
+    fn transmit(&mut self, value: u8) {
+        let result = match &mut self.output {
+            UartOutput::Stdout => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&[value]).and_then(|_| stdout.flush())
+            },
+            UartOutput::File(file) => file.write_all(&[value]),
+            UartOutput::Buffer(buffer) => {
+                buffer.push(value);
+                Ok(())
+            }
+        };
+
+        if let Err(error) = result {
+            println!("UART write failed: {}", error);
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 1 << STATUS_TX_READY;
+
+        if !self.input.is_empty() {
+            status |= 1 << STATUS_RX_READY;
+        }
+
+        status
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            UART_DATA => self.input.pop_front().unwrap_or(0),
+            _ => self.peek(offset)
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            UART_DATA => self.transmit(value),
+            UART_CONTROL => self.control = value,
+            _ => {}
+        }
+    }
+
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            UART_DATA => self.input.front().copied().unwrap_or(0),
+            UART_STATUS => self.status(),
+            UART_CONTROL => self.control,
+            _ => 0
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control & (1 << CONTROL_RX_IRQ) != 0 && !self.input.is_empty()
+    }
+
+    fn tick(&mut self, _cycles: u64) {
+        if let Some(host) = &mut self.host {
+            host.poll(&mut self.input);
+        }
+    }
+}
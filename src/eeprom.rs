@@ -0,0 +1,55 @@
+use crate::devices::output::{segments, DisplayMode, DISPLAY_DIGITS};
+use crate::microcode::microcode_rom;
+
+// 28C16, 28C64 and 28C256 parallel EEPROMs.
+pub const CHIP_SIZES: [usize; 3] = [0x800, 0x2000, 0x8000];
+
+pub const MICROCODE_CHIPS: usize = 4;
+
+// Decoder address: value in bits 0-7, digit in bits 8-9 (0 is the leftmost)
+// and the OUTPUT_MODE register value in bits 10-11.
+pub const SEGMENT_DECODER_SIZE: usize = 0x1000;
+
+// One image per byte of the control word, chip 0 holding the low byte. The
+// chips are addressed exactly like the emulator's microcode ROM.
+pub fn microcode_images(size: Option<usize>) -> Result<Vec<Vec<u8>>, String> {
+    let rom = microcode_rom();
+
+    (0..MICROCODE_CHIPS)
+        .map(|chip| {
+            let image: Vec<u8> = rom.iter().map(|word| (word >> (chip * 8)) as u8).collect();
+            pad(image, size)
+        })
+        .collect()
+}
+
+pub fn segment_decoder_image(size: Option<usize>) -> Result<Vec<u8>, String> {
+    let mut image = vec![0; SEGMENT_DECODER_SIZE];
+
+    for mode in [DisplayMode::Unsigned, DisplayMode::Signed, DisplayMode::Hex] {
+        for value in 0..=0xFF {
+            for (digit, pattern) in segments(value, mode).iter().enumerate().take(DISPLAY_DIGITS) {
+                image[(mode.to_register() as usize) << 10 | digit << 8 | value as usize] = *pattern;
+            }
+        }
+    }
+
+    pad(image, size)
+}
+
+// Pads with 0xFF, the erased state, to the given chip size or the smallest
+// chip that fits.
+fn pad(mut image: Vec<u8>, size: Option<usize>) -> Result<Vec<u8>, String> {
+    let size = match size {
+        Some(size) if !CHIP_SIZES.contains(&size) => return Err(format!("Unsupported chip size {:#X}", size)),
+        Some(size) if size < image.len() => return Err(format!("Image of {:#X} bytes does not fit a {:#X} byte chip", image.len(), size)),
+        Some(size) => size,
+        None => match CHIP_SIZES.iter().find(|&&size| size >= image.len()) {
+            Some(&size) => size,
+            None => return Err(format!("Image of {:#X} bytes is larger than any chip", image.len()))
+        }
+    };
+
+    image.resize(size, 0xFF);
+    Ok(image)
+}
@@ -0,0 +1,8 @@
+pub mod background;
+pub mod bus;
+pub mod computer;
+pub mod debugger;
+pub mod devices;
+pub mod eeprom;
+pub mod microcode;
+pub mod trace;
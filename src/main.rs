@@ -1,11 +1,66 @@
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-use computer::{Computer, GENERAL_REGISTER_NAMES, SPECIAL_REGISTER_NAMES};
+use processor_emulator::background::Background;
+use processor_emulator::bus::{Bus, WritePolicy, PAGE_EXECUTE, PAGE_READ, PAGE_WRITE};
+use processor_emulator::computer::{Computer, StopReason, GENERAL_REGISTER_NAMES, SPECIAL_REGISTER_NAMES};
+use processor_emulator::debugger::WatchHit;
+use processor_emulator::devices::disk::{Disk, DISK_SIZE};
+use processor_emulator::devices::dma::{Dma, DMA_SIZE};
+use processor_emulator::devices::framebuffer::{Framebuffer, ImageFormat, PixelFormat, FRAMEBUFFER_HEIGHT, FRAMEBUFFER_WIDTH};
+use processor_emulator::devices::keyboard::{Keyboard, KEYBOARD_SIZE};
+use processor_emulator::devices::output::{DisplayMode, OutputPort, OUTPUT_SIZE};
+use processor_emulator::devices::random::{Random, RANDOM_SIZE};
+use processor_emulator::devices::rtc::{Rtc, RTC_SIZE};
+use processor_emulator::devices::terminal::RawTerminal;
+use processor_emulator::devices::text::{TextDisplay, TEXT_HEIGHT, TEXT_WIDTH};
+use processor_emulator::devices::timer::{Timer, TIMER_SIZE};
+use processor_emulator::devices::uart::{Uart, UartOutput, UART_SIZE};
+use processor_emulator::microcode::{control_lines, Pulse};
 
-mod computer;
+const SIGINT: i32 = 2;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+extern "C" fn handle_sigint(_signum: i32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// Turns Ctrl-C into a stop request for as long as it is held, so RUN returns
+// to the prompt instead of killing the process.
+struct SigintHandler {
+    previous: usize
+}
+
+impl SigintHandler {
+    fn install() -> SigintHandler {
+        let previous = unsafe { signal(SIGINT, handle_sigint as extern "C" fn(i32) as usize) };
+
+        SigintHandler { previous }
+    }
+}
+
+impl Drop for SigintHandler {
+    fn drop(&mut self) {
+        unsafe { signal(SIGINT, self.previous) };
+    }
+}
+
+// A run on the worker thread. Ctrl-C stops it for as long as it lasts,
+// instead of killing the process with the machine in it.
+struct BackgroundRun {
+    run: Background,
+    _sigint: SigintHandler
+}
 
 fn main() {
-    let mut computer = Computer::new();
+    let computer = Arc::new(Mutex::new(Computer::new()));
+    let mut background: Option<BackgroundRun> = None;
 
     loop {
         print!(">> ");
@@ -19,18 +74,94 @@ fn main() {
             Err(error) => panic!("Problem reading line: {:?}", error),
         };
 
-        let result = execute_command(&mut computer, buffer);
+        if background.as_ref().is_some_and(|background| background.run.finished()) {
+            match background.take().unwrap().run.stop() {
+                Ok(reason) => {
+                    let mut computer = computer.lock().unwrap();
+                    print!("Background run: ");
+                    report_stop(&computer, reason);
+                    report_fault(&mut computer);
+                },
+                Err(error) => println!("{}", error)
+            }
+        }
+
+        let result = match execute_background_command(&computer, &mut background, &buffer) {
+            Some(result) => result,
+            None => execute_command(&mut computer.lock().unwrap(), buffer)
+        };
 
         if result == 0 {
             break;
         }
     }
+
+    if let Some(background) = background {
+        if let Err(error) = background.run.stop() {
+            println!("{}", error);
+        }
+    }
+}
+
+// Commands that control a run on the worker thread. Returns None for
+// anything else, which then runs against the shared machine between
+// instructions.
+fn execute_background_command(computer: &Arc<Mutex<Computer>>, background: &mut Option<BackgroundRun>, command: &str) -> Option<u8> {
+    let tokens: Vec<&str> = command.trim().split(" ").collect();
+
+    match (tokens[0], background.as_ref()) {
+        ("RUN", None) if tokens.last() == Some(&"BACKGROUND") => {
+            if tokens.len() > 3 {
+                println!("Invalid number of arguments");
+                return Some(1);
+            }
+
+            let speed = match tokens.len() {
+                3 => match tokens[1].parse::<u64>() {
+                    Ok(0) | Err(_) => {
+                        println!("Invalid speed");
+                        return Some(1);
+                    },
+                    Ok(speed) => Some(speed)
+                },
+                _ => None
+            };
+
+            // The prompt keeps the terminal, so there is no raw mode for a
+            // host keyboard to read from.
+            if computer.lock().unwrap().bus.device::<Keyboard>("KEYBOARD").is_some_and(|keyboard| keyboard.is_host()) {
+                println!("A host keyboard needs RUN in the foreground");
+                return Some(1);
+            }
+
+            INTERRUPTED.store(false, Ordering::Relaxed);
+
+            *background = Some(BackgroundRun {
+                _sigint: SigintHandler::install(),
+                run: Background::start(computer.clone(), speed, &INTERRUPTED)
+            });
+        },
+        ("PAUSE" | "RESUME" | "STOP", None) => println!("Not running in the background"),
+        ("PAUSE", Some(running)) => running.run.pause(),
+        ("RESUME", Some(running)) => running.run.resume(),
+        ("STOP", Some(_)) => {
+            match background.take().unwrap().run.stop() {
+                Ok(reason) => report_stop(&computer.lock().unwrap(), reason),
+                Err(error) => println!("{}", error)
+            }
+        },
+        ("RUN", Some(_)) => println!("Already running in the background"),
+        ("STEP" | "PULSE" | "MODE", Some(running)) if !running.run.paused() => println!("Running in the background, PAUSE first"),
+        _ => return None
+    }
+
+    Some(1)
 }
 
 fn execute_command(computer: &mut Computer, command: String) -> u8 {
     let tokens: Vec<&str> = command.trim().split(" ").collect();
 
-    if tokens.len() == 0 {
+    if tokens.is_empty() {
         println!("Empty command");
         return 1;
     } 
@@ -67,14 +198,17 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
             }
         },
         "READ" => {
-            if tokens.len() != 2 {
+            if tokens.len() != 2 && tokens.len() != 3 {
                 println!("Invalid number of arguments");
                 return 1;
             }
 
             let address = u16::from_str_radix(tokens[1], 16).unwrap();
 
-            println!("{:#04X}", computer.memory.read(address));
+            match tokens.get(2) {
+                Some(bank) => println!("{:#04X}", computer.bus.peek_bank(address, u8::from_str_radix(bank, 16).unwrap())),
+                None => println!("{:#04X}", computer.bus.peek(address))
+            }
         },
         "WRITE" => {
             if tokens.len() != 3 {
@@ -85,7 +219,7 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
             let address = u16::from_str_radix(tokens[1], 16).unwrap();
             let data = u8::from_str_radix(tokens[2], 16).unwrap();
 
-            computer.memory.write(address, data);
+            computer.bus.burn(address, data);
         },
         "DUMP" => {
             if tokens.len() != 1 {
@@ -96,7 +230,7 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
             computer.dump();
         },
         "MEMDUMP" => {
-            if tokens.len() != 3 {
+            if tokens.len() != 3 && tokens.len() != 4 {
                 println!("Invalid number of arguments");
                 return 1;
             }
@@ -105,7 +239,9 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
 
             let bytes = tokens[2].parse::<usize>().unwrap();
 
-            computer.mem_dump(address, bytes);
+            let bank = tokens.get(3).map(|bank| u8::from_str_radix(bank, 16).unwrap());
+
+            computer.mem_dump(address, bytes, bank);
         },
         "LOAD" => {
             if tokens.len() != 3 {
@@ -150,14 +286,37 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
             computer.load(start_addr, data);
         },
         "RUN" => {
-            if tokens.len() != 2 {
+            if tokens.len() > 2 {
                 println!("Invalid number of arguments");
                 return 1;
             }
 
-            let speed = tokens[1].parse::<u64>().unwrap();
+            let speed = match tokens.get(1) {
+                Some(speed) => match speed.parse::<u64>() {
+                    Ok(0) | Err(_) => {
+                        println!("Invalid speed");
+                        return 1;
+                    },
+                    Ok(speed) => Some(speed)
+                },
+                None => None
+            };
 
-            computer.run(speed);
+            let _terminal = host_terminal(computer);
+
+            INTERRUPTED.store(false, Ordering::Relaxed);
+            let _sigint = SigintHandler::install();
+
+            match computer.run(speed, &INTERRUPTED) {
+                StopReason::Halted => {},
+                StopReason::Interrupted => {
+                    println!();
+                    report_stop(computer, StopReason::Interrupted);
+                },
+                reason => report_stop(computer, reason)
+            }
+
+            report_fault(computer);
         },
         "STEP" => {
             if tokens.len() != 1 {
@@ -165,7 +324,718 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
                 return 1;
             }
 
-            computer.step();
+            let cycles = computer.step();
+            println!("{} cycles", cycles);
+
+            if let Some(hit) = computer.take_watch_hit() {
+                println!("Watchpoint {}", hit.id);
+                report_watch(hit);
+            }
+
+            report_fault(computer);
+        },
+        "MODE" => {
+            if tokens.len() > 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            match tokens.get(1) {
+                None => {},
+                Some(_) if !computer.micro.at_boundary() => {
+                    println!("Finish the current instruction first");
+                    return 1;
+                },
+                Some(&"INSTRUCTION") => computer.microcode = false,
+                Some(&"MICROCODE") => computer.microcode = true,
+                Some(_) => {
+                    println!("Invalid mode");
+                    return 1;
+                }
+            }
+
+            println!("{}", if computer.microcode { "MICROCODE" } else { "INSTRUCTION" });
+        },
+        "PULSE" => {
+            if tokens.len() > 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            if !computer.microcode {
+                println!("Not in microcode mode");
+                return 1;
+            }
+
+            let pulses = match tokens.get(1) {
+                Some(count) => count.parse::<u64>().unwrap(),
+                None => 1
+            };
+
+            for _ in 0..pulses {
+                match computer.pulse() {
+                    Pulse::Control { ir, page, step, word } => {
+                        let page_name = if page { "X" } else { "" };
+                        println!("IR: {:#04X} STEP: {}{} {:#010X} {}", ir, page_name, step, word, control_lines(word).join(" "));
+                    },
+                    Pulse::Interrupt => println!("INTERRUPT"),
+                    Pulse::Trap(cycles) => println!("TRAP {} cycles", cycles),
+                    Pulse::Idle => println!("IDLE"),
+                    Pulse::Stall(cycles) => println!("STALL {} cycles", cycles)
+                }
+
+                if let Some(hit) = computer.take_watch_hit() {
+                    println!("Watchpoint {}", hit.id);
+                    report_watch(hit);
+                    break;
+                }
+            }
+
+            report_fault(computer);
+        },
+        "MICRO" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.micro_dump();
+        },
+        "TRACE" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let result = match tokens[1] {
+                "OFF" => computer.stop_trace(),
+                _ if computer.tracing() => {
+                    println!("Already tracing");
+                    return 1;
+                },
+                file_name => computer.start_trace(file_name)
+            };
+
+            if let Err(error) = result {
+                println!("Trace failed: {}", error);
+            }
+        },
+        "BREAK" => {
+            if tokens.len() != 2 && (tokens.len() < 4 || tokens[2] != "if") {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let condition = tokens.get(3..).map(|condition| condition.join(" "));
+
+            match computer.debugger.add_breakpoint(tokens[1], condition.as_deref()) {
+                Ok(id) => println!("Breakpoint {} at {:#06X}", id, computer.debugger.breakpoints.last().unwrap().address),
+                Err(error) => println!("{}", error)
+            }
+        },
+        "WATCH" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            match computer.debugger.add_watchpoint(tokens[1], tokens.get(2).copied()) {
+                Ok(id) => {
+                    let watchpoint = computer.debugger.watchpoints.last().unwrap();
+                    println!("Watchpoint {} at {:#06X}..{:#06X}", id, watchpoint.start, watchpoint.end);
+                },
+                Err(error) => println!("{}", error)
+            }
+        },
+        "DELETE" | "DISABLE" | "ENABLE" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let id = match tokens[1].parse::<usize>() {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("Invalid breakpoint number");
+                    return 1;
+                }
+            };
+
+            let result = match command {
+                "DELETE" => computer.debugger.delete(id),
+                "DISABLE" => computer.debugger.set_enabled(id, false),
+                _ => computer.debugger.set_enabled(id, true)
+            };
+
+            if let Err(error) = result {
+                println!("{}", error);
+            }
+        },
+        "IGNORE" => {
+            if tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let (id, count) = match (tokens[1].parse::<usize>(), tokens[2].parse::<u64>()) {
+                (Ok(id), Ok(count)) => (id, count),
+                _ => {
+                    println!("Invalid breakpoint number or count");
+                    return 1;
+                }
+            };
+
+            if let Err(error) = computer.debugger.set_ignore(id, count) {
+                println!("{}", error);
+            }
+        },
+        "LIST" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            match tokens[1] {
+                "BREAKS" => {
+                    for breakpoint in &computer.debugger.breakpoints {
+                        let symbol = computer.debugger.symbol_at(breakpoint.address).map(|symbol| format!(" ({})", symbol)).unwrap_or_default();
+                        let condition = breakpoint.condition.as_ref().map(|(text, _)| format!(" if {}", text)).unwrap_or_default();
+
+                        println!(
+                            "{}: {:#06X}{}{} {} hits: {} ignore: {}",
+                            breakpoint.id, breakpoint.address, symbol, condition,
+                            if breakpoint.enabled { "enabled" } else { "disabled" }, breakpoint.hits, breakpoint.ignore
+                        );
+                    }
+                },
+                "WATCHES" => {
+                    for watchpoint in &computer.debugger.watchpoints {
+                        let access = match (watchpoint.read, watchpoint.write) {
+                            (true, true) => "rw",
+                            (true, false) => "r",
+                            _ => "w"
+                        };
+
+                        println!(
+                            "{}: {:#06X}..{:#06X} {} {} hits: {}",
+                            watchpoint.id, watchpoint.start, watchpoint.end, access,
+                            if watchpoint.enabled { "enabled" } else { "disabled" }, watchpoint.hits
+                        );
+                    }
+                },
+                "SYMBOLS" => {
+                    let mut symbols: Vec<(&String, &u16)> = computer.debugger.symbols.iter().collect();
+                    symbols.sort_by_key(|(_, &address)| address);
+
+                    for (name, address) in symbols {
+                        println!("{:#06X} {}", address, name);
+                    }
+                },
+                _ => println!("Invalid list")
+            }
+        },
+        "SYMBOLS" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            match computer.debugger.load_symbols(tokens[1]) {
+                Ok(count) => println!("{} symbols loaded", count),
+                Err(error) => println!("Could not load symbols: {}", error)
+            }
+        },
+        "LABEL" => {
+            if tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let address = u16::from_str_radix(tokens[2], 16).unwrap();
+            computer.debugger.symbols.insert(tokens[1].to_string(), address);
+        },
+        "MAP" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.bus.print_map();
+        },
+        "ROM" => {
+            if tokens.len() != 3 && tokens.len() != 4 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+            let end_addr = u16::from_str_radix(tokens[2], 16).unwrap();
+
+            let policy = match tokens.get(3) {
+                None | Some(&"IGNORE") => WritePolicy::Ignore,
+                Some(&"LOG") => WritePolicy::Log,
+                Some(&"FAULT") => WritePolicy::Fault,
+                Some(_) => {
+                    println!("Invalid write policy");
+                    return 1;
+                }
+            };
+
+            let name = format!("ROM_{:04X}", start_addr);
+
+            if let Err(error) = computer.bus.map_rom(&name, start_addr, end_addr, policy) {
+                println!("{}", error);
+            }
+        },
+        "BANKED" => {
+            if tokens.len() != 5 && tokens.len() != 6 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+            let end_addr = u16::from_str_radix(tokens[2], 16).unwrap();
+            let banks = tokens[3].parse::<usize>().unwrap();
+            let register = u16::from_str_radix(tokens[4], 16).unwrap();
+
+            let policy = match tokens.get(5) {
+                None | Some(&"RAM") => None,
+                Some(&"ROM") => Some(WritePolicy::Ignore),
+                Some(_) => {
+                    println!("Invalid bank type");
+                    return 1;
+                }
+            };
+
+            let name = format!("BANK_{:04X}", start_addr);
+
+            if let Err(error) = computer.bus.map_banked(&name, start_addr, end_addr, banks, register, policy) {
+                println!("{}", error);
+            }
+        },
+        "BANK" => {
+            if tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let bank = u8::from_str_radix(tokens[2], 16).unwrap();
+
+            if let Err(error) = computer.bus.select_bank(tokens[1], bank) {
+                println!("{}", error);
+            }
+        },
+        "PAGETABLE" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            if let Err(error) = computer.bus.map_page_table("PAGETABLE", start_addr) {
+                println!("{}", error);
+            }
+        },
+        "PROTECT" => {
+            if tokens.len() != 4 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_page = u8::from_str_radix(tokens[1], 16).unwrap();
+            let end_page = u8::from_str_radix(tokens[2], 16).unwrap();
+
+            let mut permissions = 0;
+
+            for permission in tokens[3].chars() {
+                match permission {
+                    'R' => permissions |= 1 << PAGE_READ,
+                    'W' => permissions |= 1 << PAGE_WRITE,
+                    'X' => permissions |= 1 << PAGE_EXECUTE,
+                    '-' => {},
+                    _ => {
+                        println!("Invalid permissions");
+                        return 1;
+                    }
+                }
+            }
+
+            for page in start_page..=end_page {
+                computer.bus.permissions[page as usize] = permissions;
+            }
+        },
+        "UNMAP" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            if let Err(error) = computer.bus.unmap(tokens[1]) {
+                println!("{}", error);
+            }
+        },
+        "UART" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            let output = match tokens.get(2) {
+                Some(file_name) => match std::fs::File::create(file_name) {
+                    Ok(file) => UartOutput::File(file),
+                    Err(error) => {
+                        println!("Problem creating file: {}", error);
+                        return 1;
+                    }
+                },
+                None => UartOutput::Stdout
+            };
+
+            if let Err(error) = end_address(start_addr, UART_SIZE).and_then(|end_addr| computer.bus.map_device("UART", start_addr, end_addr, Box::new(Uart::new(output)))) {
+                println!("{}", error);
+            }
+        },
+        "SEND" => {
+            if tokens.len() < 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let mut text = tokens[1..].join(" ");
+            text.push('\n');
+
+            match computer.bus.device_mut::<Uart>("UART") {
+                Some(uart) => uart.push_input(text.as_bytes()),
+                None => println!("No UART mapped")
+            }
+        },
+        "OUTPUT" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            if let Err(error) = end_address(start_addr, OUTPUT_SIZE).and_then(|end_addr| computer.bus.map_device("OUTPUT", start_addr, end_addr, Box::new(OutputPort::new()))) {
+                println!("{}", error);
+            }
+        },
+        "DISPLAY" => {
+            if tokens.len() > 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let output = match computer.bus.device_mut::<OutputPort>("OUTPUT") {
+                Some(output) => output,
+                None => {
+                    println!("No output register mapped");
+                    return 1;
+                }
+            };
+
+            match tokens.get(1) {
+                None => {},
+                Some(&"UNSIGNED") => output.mode = DisplayMode::Unsigned,
+                Some(&"SIGNED") => output.mode = DisplayMode::Signed,
+                Some(&"HEX") => output.mode = DisplayMode::Hex,
+                Some(&"LOG") => {
+                    for value in output.history() {
+                        print!("{:#04X} ", value);
+                    }
+
+                    println!();
+                    return 1;
+                },
+                Some(_) => {
+                    println!("Invalid display mode");
+                    return 1;
+                }
+            }
+
+            println!("{}", output.render());
+        },
+        "TIMER" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            if let Err(error) = end_address(start_addr, TIMER_SIZE).and_then(|end_addr| computer.bus.map_device("TIMER", start_addr, end_addr, Box::new(Timer::new()))) {
+                println!("{}", error);
+            }
+        },
+        "TEXT" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            let attributes = match tokens.get(2) {
+                None => false,
+                Some(&"ATTR") => true,
+                Some(_) => {
+                    println!("Invalid text display option");
+                    return 1;
+                }
+            };
+
+            let display = TextDisplay::new(TEXT_WIDTH, TEXT_HEIGHT, attributes);
+            let size = display.size();
+
+            if let Err(error) = end_address(start_addr, size).and_then(|end_addr| computer.bus.map_device("TEXT", start_addr, end_addr, Box::new(display))) {
+                println!("{}", error);
+            }
+        },
+        "SCREEN" => {
+            if tokens.len() > 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let display = match computer.bus.device::<TextDisplay>("TEXT") {
+                Some(display) => display,
+                None => {
+                    println!("No text display mapped");
+                    return 1;
+                }
+            };
+
+            match tokens.get(1) {
+                Some(file_name) => {
+                    if let Err(error) = std::fs::write(file_name, display.dump_text() + "\n") {
+                        println!("Problem writing file: {}", error);
+                    }
+                },
+                None => println!("{}", display.render())
+            }
+        },
+        "FRAMEBUFFER" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            let format = match tokens.get(2) {
+                None | Some(&"MONO") => PixelFormat::Mono,
+                Some(&"COLOR") => PixelFormat::Palette4,
+                Some(_) => {
+                    println!("Invalid pixel format");
+                    return 1;
+                }
+            };
+
+            let framebuffer = Framebuffer::new(FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT, format);
+            let size = framebuffer.size();
+
+            if let Err(error) = end_address(start_addr, size).and_then(|end_addr| computer.bus.map_device("FRAMEBUFFER", start_addr, end_addr, Box::new(framebuffer))) {
+                println!("{}", error);
+            }
+        },
+        "SNAPSHOT" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            match computer.bus.device::<Framebuffer>("FRAMEBUFFER") {
+                Some(framebuffer) => {
+                    if let Err(error) = framebuffer.export(tokens[1], ImageFormat::from_file_name(tokens[1])) {
+                        println!("Problem writing file: {}", error);
+                    }
+                },
+                None => println!("No framebuffer mapped")
+            }
+        },
+        "RECORD" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let framebuffer = match computer.bus.device_mut::<Framebuffer>("FRAMEBUFFER") {
+                Some(framebuffer) => framebuffer,
+                None => {
+                    println!("No framebuffer mapped");
+                    return 1;
+                }
+            };
+
+            match (tokens[1], tokens.get(2)) {
+                ("OFF", None) => println!("{} frames written", framebuffer.stop_recording()),
+                (prefix, None | Some(&"PPM")) => framebuffer.start_recording(prefix, ImageFormat::Ppm),
+                (prefix, Some(&"PNG")) => framebuffer.start_recording(prefix, ImageFormat::Png),
+                (_, Some(_)) => println!("Invalid image format")
+            }
+        },
+        "DISK" => {
+            if tokens.len() != 3 && tokens.len() != 4 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            let writable = match tokens.get(3) {
+                None | Some(&"RO") => false,
+                Some(&"RW") => true,
+                Some(_) => {
+                    println!("Invalid disk mode");
+                    return 1;
+                }
+            };
+
+            let disk = match Disk::open(tokens[2], writable) {
+                Ok(disk) => disk,
+                Err(error) => {
+                    println!("Problem opening disk image: {}", error);
+                    return 1;
+                }
+            };
+
+            if let Err(error) = end_address(start_addr, DISK_SIZE).and_then(|end_addr| computer.bus.map_device("DISK", start_addr, end_addr, Box::new(disk))) {
+                println!("{}", error);
+            }
+        },
+        "DMA" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            if let Err(error) = end_address(start_addr, DMA_SIZE).and_then(|end_addr| computer.bus.map_device("DMA", start_addr, end_addr, Box::new(Dma::new()))) {
+                println!("{}", error);
+            }
+        },
+        "KEYBOARD" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+
+            let keyboard = match tokens.get(2) {
+                Some(script) => match Keyboard::from_script(script) {
+                    Ok(keyboard) => keyboard,
+                    Err(error) => {
+                        println!("Problem reading key script: {}", error);
+                        return 1;
+                    }
+                },
+                None => Keyboard::host()
+            };
+
+            if let Err(error) = end_address(start_addr, KEYBOARD_SIZE).and_then(|end_addr| computer.bus.map_device("KEYBOARD", start_addr, end_addr, Box::new(keyboard))) {
+                println!("{}", error);
+            }
+        },
+        "RANDOM" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+            let seed = tokens.get(2).map_or(1, |seed| u32::from_str_radix(seed, 16).unwrap());
+
+            if let Err(error) = end_address(start_addr, RANDOM_SIZE).and_then(|end_addr| computer.bus.map_device("RANDOM", start_addr, end_addr, Box::new(Random::new(seed)))) {
+                println!("{}", error);
+            }
+        },
+        "SEED" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let seed = u32::from_str_radix(tokens[1], 16).unwrap();
+
+            match computer.bus.device_mut::<Random>("RANDOM") {
+                Some(random) => random.seed(seed),
+                None => println!("No random number generator mapped")
+            }
+        },
+        "RTC" => {
+            if tokens.len() != 2 && tokens.len() != 3 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let start_addr = u16::from_str_radix(tokens[1], 16).unwrap();
+            let fixed = tokens.get(2).map(|timestamp| timestamp.parse::<u64>().unwrap());
+
+            if let Err(error) = end_address(start_addr, RTC_SIZE).and_then(|end_addr| computer.bus.map_device("RTC", start_addr, end_addr, Box::new(Rtc::new(fixed)))) {
+                println!("{}", error);
+            }
+        },
+        "CLOCK" => {
+            if tokens.len() != 2 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            let fixed = match tokens[1] {
+                "HOST" => None,
+                timestamp => Some(timestamp.parse::<u64>().unwrap())
+            };
+
+            match computer.bus.device_mut::<Rtc>("RTC") {
+                Some(rtc) => rtc.fixed = fixed,
+                None => println!("No real-time clock mapped")
+            }
+        },
+        "IRQ" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.raise_irq();
+        },
+        "NMI" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.raise_nmi();
+        },
+        "RESET" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.reset();
+        },
+        "POWERON" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.power_on();
+        },
+        "STATUS" => {
+            if tokens.len() != 1 {
+                println!("Invalid number of arguments");
+                return 1;
+            }
+
+            computer.interrupt_status();
         },
         "END" => {
             return 0;
@@ -178,12 +1048,66 @@ fn execute_command(computer: &mut Computer, command: String) -> u8 {
     1
 }
 
+fn report_stop(computer: &Computer, reason: StopReason) {
+    let pc = computer.cpu.special_registers[0].value;
+
+    let location = match computer.debugger.symbol_at(pc) {
+        Some(symbol) => format!("{:#06X} ({})", pc, symbol),
+        None => format!("{:#06X}", pc)
+    };
+
+    match reason {
+        StopReason::Halted => println!("Halted at {} after {} cycles", location, computer.cycles),
+        StopReason::Interrupted => println!("Interrupted at {} after {} cycles", location, computer.cycles),
+        StopReason::Breakpoint(id) => println!("Breakpoint {} at {} after {} cycles", id, location, computer.cycles),
+        StopReason::Watchpoint(hit) => {
+            println!("Watchpoint {} at {} after {} cycles", hit.id, location, computer.cycles);
+            report_watch(hit);
+        }
+    }
+}
+
+fn report_watch(hit: WatchHit) {
+    if hit.write {
+        println!("Write to {:#06X} by {:#06X}: {:#04X} -> {:#04X}", hit.address, hit.pc, hit.old, hit.new);
+    } else {
+        println!("Read of {:#06X} by {:#06X}: {:#04X}", hit.address, hit.pc, hit.new);
+    }
+}
+
+// Hands the host terminal to a host keyboard, or else to the UART, and puts
+// it in raw mode for the run when either takes it.
+fn host_terminal(computer: &mut Computer) -> Option<RawTerminal> {
+    let host_keyboard = computer.bus.device::<Keyboard>("KEYBOARD").is_some_and(|keyboard| keyboard.is_host());
+
+    let host_uart = match computer.bus.device_mut::<Uart>("UART") {
+        Some(uart) => {
+            uart.set_host_input(!host_keyboard);
+            !host_keyboard
+        },
+        None => false
+    };
+
+    if host_keyboard || host_uart { RawTerminal::enable().ok() } else { None }
+}
+
+fn report_fault(computer: &mut Computer) {
+    if let Some(fault) = computer.fault.take() {
+        println!("Bus fault: write of {:#04X} to ROM at {:#06X}", fault.value, fault.addr);
+    }
+}
+
+// Last address of a device of `size` bytes mapped at `start_addr`.
+fn end_address(start_addr: u16, size: u16) -> Result<u16, String> {
+    start_addr.checked_add(size - 1).ok_or("Address range out of bounds".to_string())
+}
+
 fn index_if_contains<T: std::cmp::PartialEq>(target: T, array: &[T], size: usize) -> isize {
-    for i in 0..size {
-        if array[i] == target {
+    for (i, item) in array.iter().take(size).enumerate() {
+        if *item == target {
             return i as isize;
         }
     } 
 
-    return -1;
+    -1
 }
\ No newline at end of file
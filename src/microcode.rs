@@ -0,0 +1,276 @@
+use std::sync::OnceLock;
+
+use crate::computer::{IDLE_CYCLES, INTERRUPT_CYCLES};
+
+// Control word layout. The low byte holds three encoded fields (bus source,
+// ALU operation and address source), the rest are individual control lines.
+pub const OUT_MASK: u32 = 0x7;
+pub const OUT_RAM: u32 = 0x1;
+pub const OUT_TMP: u32 = 0x2;
+pub const OUT_RD: u32 = 0x3;
+pub const OUT_RS: u32 = 0x4;
+pub const OUT_ALU: u32 = 0x5;
+pub const OUT_OR: u32 = 0x6;
+
+pub const ALU_SHIFT: u32 = 3;
+pub const ALU_MASK: u32 = 0x7 << ALU_SHIFT;
+pub const ALU_ADD: u32 = 0x0 << ALU_SHIFT;
+pub const ALU_ADC: u32 = 0x1 << ALU_SHIFT;
+pub const ALU_SUB: u32 = 0x2 << ALU_SHIFT;
+pub const ALU_NAND: u32 = 0x3 << ALU_SHIFT;
+pub const ALU_NOR: u32 = 0x4 << ALU_SHIFT;
+pub const ALU_CMP: u32 = 0x5 << ALU_SHIFT;
+
+pub const ADDR_SHIFT: u32 = 6;
+pub const ADDR_MASK: u32 = 0x3 << ADDR_SHIFT;
+pub const ADDR_MAR: u32 = 0x0 << ADDR_SHIFT;
+pub const ADDR_PC: u32 = 0x1 << ADDR_SHIFT;
+pub const ADDR_SP: u32 = 0x2 << ADDR_SHIFT;
+pub const ADDR_HL: u32 = 0x3 << ADDR_SHIFT;
+
+pub const RI: u32 = 1 << 8; // RAM in
+pub const II: u32 = 1 << 9; // instruction register in
+pub const OI: u32 = 1 << 10; // operand register in
+pub const TI: u32 = 1 << 11; // temporary register in
+pub const RDI: u32 = 1 << 12; // register selected by IR in
+pub const FI: u32 = 1 << 13; // flags in from the ALU
+pub const CE: u32 = 1 << 14; // PC increment
+pub const SPI: u32 = 1 << 15; // SP increment, after the access
+pub const SPD: u32 = 1 << 16; // SP decrement, before the access
+pub const MW: u32 = 1 << 17; // MAR in, high byte from the bus, low byte from TMP
+pub const MHL: u32 = 1 << 18; // MAR in from HL
+pub const HLM: u32 = 1 << 19; // HL in from MAR
+pub const MADD: u32 = 1 << 20; // MAR += TMP
+pub const JR: u32 = 1 << 21; // PC += MAR
+pub const J: u32 = 1 << 22; // PC in from MAR
+pub const HLC: u32 = 1 << 23; // HL count, down when IR bit 6 is set
+pub const SI: u32 = 1 << 24; // status register in
+pub const FBI: u32 = 1 << 25; // flags in from the bus
+pub const IEL: u32 = 1 << 26; // interrupt enable in, from IR bit 4 inverted
+pub const PG: u32 = 1 << 27; // switch to the extended page, restarting the step counter
+pub const SR: u32 = 1 << 28; // step reset, ends the instruction
+pub const PRIV: u32 = 1 << 29; // trap unless in supervisor mode
+pub const HLT: u32 = 1 << 30; // halt
+pub const SYS: u32 = 1 << 31; // enter the SYSCALL trap sequence
+
+const LINE_NAMES: [&str; 24] = [
+    "RI", "II", "OI", "TI", "RDI", "FI", "CE", "SPI", "SPD", "MW", "MHL", "HLM",
+    "MADD", "JR", "J", "HLC", "SI", "FBI", "IEL", "PG", "SR", "PRIV", "HLT", "SYS"
+];
+const OUT_NAMES: [&str; 8] = ["", "RO", "TO", "RDO", "RSO", "EO", "OO", ""];
+const ALU_NAMES: [&str; 8] = ["ADD", "ADC", "SUB", "NAND", "NOR", "CMP", "", ""];
+const ADDR_NAMES: [&str; 4] = ["MAR", "PC", "SP", "HL"];
+
+pub const MAX_STEPS: usize = 8;
+pub const ROM_SIZE: usize = 2048;
+
+pub const FETCH: u32 = ADDR_PC | OUT_RAM | II | CE;
+
+// Registers that only exist in the microcoded machine: the instruction and
+// operand registers, the ALU's second input, the memory address register and
+// the step counter.
+#[derive(Copy, Clone, Default)]
+pub struct MicroState {
+    pub ir: u8,
+    pub or: u8,
+    pub tmp: u8,
+    pub mar: u16,
+    pub step: u8,
+    pub page: bool
+}
+
+impl MicroState {
+    pub fn at_boundary(&self) -> bool {
+        self.step == 0 && !self.page
+    }
+}
+
+pub enum Pulse {
+    Control { ir: u8, page: bool, step: u8, word: u32 },
+    Interrupt,
+    Trap(u64),
+    Idle,
+    Stall(u64)
+}
+
+impl Pulse {
+    pub fn cycles(&self) -> u64 {
+        match self {
+            Pulse::Control { .. } => 1,
+            Pulse::Interrupt => INTERRUPT_CYCLES,
+            Pulse::Idle => IDLE_CYCLES,
+            Pulse::Trap(cycles) | Pulse::Stall(cycles) => *cycles
+        }
+    }
+}
+
+// Returns the result, the carry out and whether the result is zero. ADC only
+// carries out of its final add, like the instruction-level ADC.
+pub fn alu(operation: u32, a: u8, b: u8, carry: bool) -> (u8, bool, bool) {
+    let (result, carry) = match operation {
+        ALU_ADD => a.overflowing_add(b),
+        ALU_ADC => {
+            let result = a.overflowing_add(b);
+
+            if carry { result.0.overflowing_add(1) } else { result }
+        },
+        ALU_SUB | ALU_CMP => (a.wrapping_sub(b), false),
+        ALU_NAND => (!(a & b), false),
+        _ => (!(a | b), false)
+    };
+
+    (result, carry, result == 0)
+}
+
+// ROM address: step in bits 0-2, the instruction's mode bit in bit 3, opcode
+// in bits 4-7, ZERO and CARRY flags in bits 8-9 and the extended page in bit
+// 10.
+pub fn rom_address(page: bool, flags: u8, instruction: u8, step: u8) -> usize {
+    (step as usize & 0x7) | (instruction as usize & 0xF8) | (flags as usize & 0x3) << 8 | (page as usize) << 10
+}
+
+pub fn microcode_rom() -> &'static [u32] {
+    static ROM: OnceLock<Vec<u32>> = OnceLock::new();
+
+    ROM.get_or_init(|| {
+        let mut rom = vec![SR; ROM_SIZE];
+
+        for page in [false, true] {
+            for flags in 0..4 {
+                for instruction in (0..=0xF8).step_by(8) {
+                    let steps = instruction_steps(page, flags, instruction as u8);
+
+                    for (step, &word) in steps.iter().enumerate() {
+                        rom[rom_address(page, flags, instruction as u8, step as u8)] = word;
+                    }
+                }
+            }
+        }
+
+        rom
+    })
+}
+
+// Pulses the instruction takes in this page: up to and including the step
+// that ends it or continues in the extended page. Instruction mode charges
+// the same, so both modes keep the same clock.
+pub fn instruction_cycles(page: bool, flags: u8, instruction: u8) -> u64 {
+    static CYCLES: OnceLock<Vec<u64>> = OnceLock::new();
+
+    let cycles = CYCLES.get_or_init(|| {
+        microcode_rom()
+            .chunks(MAX_STEPS)
+            .map(|steps| steps.iter().position(|&word| word & (SR | PG) != 0).map_or(MAX_STEPS, |step| step + 1) as u64)
+            .collect()
+    });
+
+    cycles[rom_address(page, flags, instruction, 0) / MAX_STEPS]
+}
+
+pub fn control_lines(word: u32) -> Vec<&'static str> {
+    let mut lines = Vec::new();
+
+    if word & OUT_MASK == OUT_RAM || word & RI != 0 {
+        lines.push(ADDR_NAMES[((word & ADDR_MASK) >> ADDR_SHIFT) as usize]);
+    }
+
+    if word & OUT_MASK != 0 {
+        lines.push(OUT_NAMES[(word & OUT_MASK) as usize]);
+    }
+
+    if word & OUT_MASK == OUT_ALU || word & FI != 0 {
+        lines.push(ALU_NAMES[((word & ALU_MASK) >> ALU_SHIFT) as usize]);
+    }
+
+    for (i, name) in LINE_NAMES.iter().enumerate() {
+        if word & (1 << (i + 8)) != 0 {
+            lines.push(name);
+        }
+    }
+
+    lines
+}
+
+// Control words for one instruction. Base page instructions start with the
+// shared fetch step; extended instructions continue after the prefix. The
+// last step also resets the step counter, unless it switches page. The fetch
+// step is shared by every instruction, so it can't end one itself.
+fn instruction_steps(page: bool, flags: u8, instruction: u8) -> Vec<u32> {
+    let mut steps = instruction_body(page, flags, instruction);
+
+    match steps.last_mut() {
+        Some(last) if *last & PG == 0 => *last |= SR,
+        Some(_) => {},
+        None => steps.push(SR)
+    }
+
+    if !page {
+        steps.insert(0, FETCH);
+    }
+
+    steps
+}
+
+fn instruction_body(page: bool, flags: u8, instruction: u8) -> Vec<u32> {
+    let opcode = instruction >> 4;
+    let register_mode = instruction & 0x8 != 0;
+    let zero = flags & 0x1 != 0;
+
+    let fetch_operand = ADDR_PC | OUT_RAM | TI | CE;
+    let fetch_address = [fetch_operand, ADDR_PC | OUT_RAM | MW | CE];
+
+    if page {
+        return match opcode {
+            0x0 => vec![fetch_operand, MHL, MADD, OUT_RAM | RDI],
+            0x1 => vec![fetch_operand, MHL, MADD, OUT_RD | RI],
+            0x2 | 0x4 => vec![ADDR_HL | OUT_RAM | RDI | HLC],
+            0x3 | 0x5 => vec![ADDR_HL | OUT_RD | RI | HLC],
+            0x6 | 0x7 => vec![PRIV | IEL],
+            0x8 => vec![
+                PRIV | ADDR_SP | OUT_RAM | OI | SPI,
+                ADDR_SP | OUT_RAM | FBI | SPI,
+                ADDR_SP | OUT_RAM | TI | SPI,
+                ADDR_SP | OUT_RAM | MW | SPI,
+                J | OUT_OR | SI
+            ],
+            0x9 => vec![SYS],
+            _ => vec![SR]
+        };
+    }
+
+    match (opcode, register_mode) {
+        (0x0, false) => vec![],
+        (0x0, true) => vec![ADDR_PC | OUT_RAM | II | CE | PG],
+        (0x1, false) => vec![ADDR_PC | OUT_RAM | RDI | CE],
+        (0x1, true) => vec![ADDR_PC | OUT_RAM | OI | CE, OUT_RS | RDI],
+        (0x2, false) => [&fetch_address[..], &[OUT_RAM | RDI]].concat(),
+        (0x2, true) => vec![ADDR_HL | OUT_RAM | RDI],
+        (0x3, false) => [&fetch_address[..], &[OUT_RD | RI]].concat(),
+        (0x3, true) => vec![ADDR_HL | OUT_RD | RI],
+        (0x4, _) => [&fetch_address[..], &[HLM]].concat(),
+        (0x5, false) => vec![fetch_operand, SPD | ADDR_SP | OUT_TMP | RI],
+        (0x5, true) => vec![SPD | ADDR_SP | OUT_RD | RI],
+        (0x6, _) => vec![ADDR_SP | OUT_RAM | RDI | SPI],
+        (0x7, false) => [&fetch_address[..], &[JR]].concat(),
+        (0x7, true) => vec![MHL, JR],
+        (0x8, false) => [&fetch_address[..], if zero { &[][..] } else { &[JR][..] }].concat(),
+        (0x8, true) => if zero { vec![] } else { vec![MHL, JR] },
+        (0x9..=0xE, _) => {
+            let execute = match opcode {
+                0x9 => ALU_ADD | OUT_ALU | RDI | FI,
+                0xA => ALU_ADC | OUT_ALU | RDI | FI,
+                0xB => ALU_CMP | FI,
+                0xC => ALU_SUB | OUT_ALU | RDI,
+                0xD => ALU_NAND | OUT_ALU | RDI,
+                _ => ALU_NOR | OUT_ALU | RDI
+            };
+
+            if register_mode {
+                vec![ADDR_PC | OUT_RAM | OI | CE, OUT_RS | TI, execute]
+            } else {
+                vec![fetch_operand, execute]
+            }
+        },
+        _ => vec![PRIV | HLT]
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::bus::BusAccess;
+use crate::computer::CPU;
+
+// Traced signals and their widths. The bus signals hold the last access; EN
+// is high on cycles where the bus is in use and RW is 1 for a read, 0 for a
+// write.
+const SIGNALS: [(&str, u32); 14] = [
+    ("PC", 16), ("SP", 16),
+    ("A", 8), ("B", 8), ("C", 8), ("D", 8), ("L", 8), ("H", 8),
+    ("F", 8), ("S", 8),
+    ("ADDR", 16), ("DATA", 8), ("RW", 1), ("EN", 1)
+];
+
+const ADDR: usize = 10;
+const DATA: usize = 11;
+const RW: usize = 12;
+const EN: usize = 13;
+
+// Value Change Dump writer with one time unit per emulated cycle.
+pub struct VcdTrace<W: Write = BufWriter<File>> {
+    out: W,
+    values: [Option<u64>; SIGNALS.len()],
+    time: Option<u64>
+}
+
+impl VcdTrace {
+    pub fn create(path: &str) -> io::Result<VcdTrace> {
+        VcdTrace::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> VcdTrace<W> {
+    pub fn new(mut out: W) -> io::Result<VcdTrace<W>> {
+        writeln!(out, "$version processor-emulator $end")?;
+        writeln!(out, "$timescale 1 us $end")?;
+        writeln!(out, "$scope module computer $end")?;
+
+        for (i, (name, width)) in SIGNALS.iter().enumerate() {
+            writeln!(out, "$var wire {} {} {} $end", width, identifier(i), name)?;
+        }
+
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        Ok(VcdTrace {
+            out,
+            values: [None; SIGNALS.len()],
+            time: None
+        })
+    }
+
+    // Records a step that started at `start` and took `cycles`. The bus
+    // accesses are spread evenly over the step, each holding EN for one
+    // cycle, and the registers change on its last cycle.
+    pub fn record(&mut self, start: u64, cycles: u64, cpu: &CPU, accesses: &[BusAccess]) -> io::Result<()> {
+        let end = start + cycles.max(1);
+        let times: Vec<u64> = (0..accesses.len()).map(|i| start + i as u64 * cycles / accesses.len() as u64).collect();
+
+        for (i, access) in accesses.iter().enumerate() {
+            self.change(times[i], ADDR, access.addr as u64)?;
+            self.change(times[i], DATA, access.value as u64)?;
+            self.change(times[i], RW, if access.write { 0 } else { 1 })?;
+            self.change(times[i], EN, 1)?;
+
+            // Idle until the next access, or the end of the step.
+            let idle = times[i] + 1;
+
+            if idle < times.get(i + 1).copied().unwrap_or(end) {
+                self.change(idle, EN, 0)?;
+            }
+        }
+
+        if accesses.is_empty() {
+            self.change(start, EN, 0)?;
+        }
+
+        let end = end - 1;
+
+        self.change(end, 0, cpu.special_registers[0].value as u64)?;
+        self.change(end, 1, cpu.special_registers[1].value as u64)?;
+
+        for (i, register) in cpu.general_registers.iter().enumerate() {
+            self.change(end, 2 + i, register.value as u64)?;
+        }
+
+        self.change(end, 8, (cpu.special_registers[2].value & 0xFF) as u64)?;
+        self.change(end, 9, (cpu.special_registers[3].value & 0xFF) as u64)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn change(&mut self, time: u64, signal: usize, value: u64) -> io::Result<()> {
+        if self.values[signal] == Some(value) {
+            return Ok(());
+        }
+
+        match self.time {
+            Some(last) if time < last => return Err(io::Error::other(format!("Trace time went back from {} to {}", last, time))),
+            Some(last) if time == last => {},
+            _ => {
+                writeln!(self.out, "#{}", time)?;
+                self.time = Some(time);
+            }
+        }
+
+        self.values[signal] = Some(value);
+
+        match SIGNALS[signal].1 {
+            1 => writeln!(self.out, "{}{}", value, identifier(signal)),
+            _ => writeln!(self.out, "b{:b} {}", value, identifier(signal))
+        }
+    }
+}
+
+fn identifier(signal: usize) -> char {
+    (b'!' + signal as u8) as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(trace: VcdTrace<Vec<u8>>) -> String {
+        let text = String::from_utf8(trace.finish().unwrap()).unwrap();
+        text.split_once("$enddefinitions $end\n").unwrap().1.to_string()
+    }
+
+    fn read(addr: u16, value: u8) -> BusAccess {
+        BusAccess { addr, value, write: false }
+    }
+
+    #[test]
+    fn enable_drops_between_and_after_accesses() {
+        let mut trace = VcdTrace::new(Vec::new()).unwrap();
+        let mut cpu = CPU::new();
+        cpu.special_registers[0].value = 3;
+        cpu.general_registers[0].value = 0x42;
+
+        // 08 00 05 (LDR A, [HL+5]) with HL 0x1000: four accesses in six cycles.
+        let accesses = [read(0x0000, 0x08), read(0x0001, 0x00), read(0x0002, 0x05), read(0x1005, 0x42)];
+        trace.record(0, 6, &cpu, &accesses).unwrap();
+
+        let expected = [
+            "#0", "b0 +", "b1000 ,", "1-", "1.",
+            "#1", "b1 +", "b0 ,",
+            "#2", "0.",
+            "#3", "b10 +", "b101 ,", "1.",
+            "#4", "b1000000000101 +", "b1000010 ,",
+            "#5", "0.",
+            "b11 !", "b0 \"", "b1000010 #", "b0 $", "b0 %", "b0 &", "b0 '", "b0 (", "b0 )", "b0 *"
+        ];
+
+        assert_eq!(dump(trace), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn enable_stays_high_when_accesses_fill_the_step() {
+        let mut trace = VcdTrace::new(Vec::new()).unwrap();
+        let cpu = CPU::new();
+
+        trace.record(0, 2, &cpu, &[read(0x0000, 0x60), read(0x8000, 0x01)]).unwrap();
+        trace.record(2, 1, &cpu, &[]).unwrap();
+
+        let text = dump(trace);
+        let en: Vec<&str> = text.lines().filter(|line| line.starts_with('#') || line.ends_with('.')).collect();
+
+        assert_eq!(en, vec!["#0", "1.", "#1", "#2", "0."]);
+    }
+
+    #[test]
+    fn time_never_goes_backwards() {
+        let mut trace = VcdTrace::new(Vec::new()).unwrap();
+        let cpu = CPU::new();
+
+        trace.record(10, 1, &cpu, &[read(0x0000, 0x00)]).unwrap();
+        assert!(trace.record(5, 1, &cpu, &[read(0x0001, 0x01)]).is_err());
+    }
+}